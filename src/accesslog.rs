@@ -0,0 +1,308 @@
+/*
+ *    Copyright (C) 2016-2018 Stefan Luecke
+ *                  2018 Nicolas Martin
+ *
+ *    This program is free software: you can redistribute it and/or modify
+ *    it under the terms of the GNU Affero General Public License as published
+ *    by the Free Software Foundation, either version 3 of the License, or
+ *    (at your option) any later version.
+ *
+ *    This program is distributed in the hope that it will be useful,
+ *    but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *    GNU Affero General Public License for more details.
+ *
+ *    You should have received a copy of the GNU Affero General Public License
+ *    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ *    Authors: Stefan Luecke <glaxx@glaxx.net>
+ *             Nicolas Martin <penguwingit@gmail.com>
+ */
+//! Access log with one record per request.
+use super::std;
+use daemon::LogFile;
+use gophertype::GopherType;
+use std::io::{Error, Write};
+use std::net::SocketAddr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct",
+    "Nov", "Dec",
+];
+
+/// The format records are written in.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    /// A line similar to the Common Log Format of web servers.
+    Common,
+    /// A JSON object per line.
+    Json,
+}
+
+impl Default for Format {
+    fn default() -> Self {
+        Format::Common
+    }
+}
+
+/// How a request ended.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Status {
+    /// The requested item was sent.
+    Ok,
+    /// The requested item doesn't exist.
+    NotFound,
+    /// The client may not request the item.
+    Forbidden,
+    /// The request couldn't be understood.
+    BadRequest,
+    /// The client took too long.
+    Timeout,
+    /// The connection failed.
+    Failed,
+}
+
+impl std::fmt::Display for Status {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(match *self {
+            Status::Ok => "ok",
+            Status::NotFound => "not-found",
+            Status::Forbidden => "forbidden",
+            Status::BadRequest => "bad-request",
+            Status::Timeout => "timeout",
+            Status::Failed => "failed",
+        })
+    }
+}
+
+/// A single request.
+#[derive(Debug, Clone)]
+pub struct Record {
+    /// When the connection was accepted.
+    pub time: SystemTime,
+    pub peer: SocketAddr,
+    /// The requested selector, `None` if no request was received.
+    pub selector: Option<String>,
+    pub search: Option<String>,
+    /// The type of the item served.
+    pub gtype: Option<GopherType>,
+    /// Bytes of the response sent.
+    pub bytes: usize,
+    /// Time from accepting the connection until the response was sent.
+    pub duration: Duration,
+    pub status: Status,
+}
+
+impl Record {
+    /// Starts the record of a connection accepted just now. Until told
+    /// otherwise, the request failed.
+    pub fn new(peer: SocketAddr) -> Record {
+        Record {
+            time: SystemTime::now(),
+            peer: peer,
+            selector: None,
+            search: None,
+            gtype: None,
+            bytes: 0,
+            duration: Duration::from_secs(0),
+            status: Status::Failed,
+        }
+    }
+
+    /// Formats the record as a line in `format`, including the newline.
+    pub fn format(&self, format: Format) -> String {
+        let (date, time) = utc(self.time);
+        let millis = self.duration.as_secs() * 1000
+            + u64::from(self.duration.subsec_millis());
+        match format {
+            Format::Common => format!(
+                "{} - - [{:02}/{}/{:04}:{:02}:{:02}:{:02} +0000] {} {} {} {} \
+                 {} {}\n",
+                self.peer.ip(),
+                date.2,
+                MONTHS[date.1 as usize - 1],
+                date.0,
+                time.0,
+                time.1,
+                time.2,
+                self.selector.as_ref().map_or("-".to_string(), |s| quote(s)),
+                self.search.as_ref().map_or("-".to_string(), |s| quote(s)),
+                self.gtype
+                    .as_ref()
+                    .map_or("-".to_string(), |t| t.to_string()),
+                self.status,
+                self.bytes,
+                millis
+            ),
+            Format::Json => format!(
+                "{{\"time\":\"{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z\",\
+                 \"peer\":\"{}\",\"selector\":{},\"search\":{},\
+                 \"type\":{},\"status\":\"{}\",\"bytes\":{},\
+                 \"duration_ms\":{}}}\n",
+                date.0,
+                date.1,
+                date.2,
+                time.0,
+                time.1,
+                time.2,
+                self.peer,
+                json_string(&self.selector),
+                json_string(&self.search),
+                json_string(&self.gtype.as_ref().map(|t| t.to_string())),
+                self.status,
+                self.bytes,
+                millis
+            ),
+        }
+    }
+}
+
+/// Splits `time` into the UTC date (year, month, day) and time of day
+/// (hours, minutes, seconds).
+fn utc(time: SystemTime) -> ((i64, u32, u32), (u64, u64, u64)) {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let clock = (secs / 3600 % 24, secs / 60 % 60, secs % 60);
+
+    // Converts days since 1970-01-01 to a date of the proleptic Gregorian
+    // calendar, see http://howardhinnant.github.io/date_algorithms.html
+    let days = (secs / 86400) as i64 + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days - era * 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524
+        - day_of_era / 146_096)
+        / 365;
+    let day_of_year =
+        day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    ((year, month, day), clock)
+}
+
+/// Quotes `s` for the common format, escaping quotes, backslashes and
+/// control characters so every record stays on a single line.
+fn quote(s: &str) -> String {
+    let mut res = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' | '\\' => {
+                res.push('\\');
+                res.push(c);
+            }
+            c if c.is_control() => {
+                res.push_str(&format!("\\x{:02x}", c as u32))
+            }
+            c => res.push(c),
+        }
+    }
+    res.push('"');
+    res
+}
+
+/// Encodes `s` as JSON string, or `null`.
+fn json_string(s: &Option<String>) -> String {
+    let s = match *s {
+        Some(ref s) => s,
+        None => return "null".to_string(),
+    };
+    let mut res = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => res.push_str("\\\""),
+            '\\' => res.push_str("\\\\"),
+            '\n' => res.push_str("\\n"),
+            '\r' => res.push_str("\\r"),
+            '\t' => res.push_str("\\t"),
+            c if c.is_control() => {
+                res.push_str(&format!("\\u{:04x}", c as u32))
+            }
+            c => res.push(c),
+        }
+    }
+    res.push('"');
+    res
+}
+
+/// The access log file. Clones write to the same file.
+#[derive(Clone)]
+pub struct AccessLog {
+    format: Format,
+    file: LogFile,
+}
+
+impl AccessLog {
+    /// Opens the access log at `path` for appending.
+    pub fn open(path: &str, format: Format) -> Result<AccessLog, Error> {
+        Ok(AccessLog {
+            format: format,
+            file: LogFile::open(path)?,
+        })
+    }
+
+    /// The underlying file, e.g. for reopening it.
+    pub fn file(&self) -> &LogFile {
+        &self.file
+    }
+
+    /// Appends `record` to the log.
+    pub fn log(&mut self, record: &Record) -> Result<(), Error> {
+        self.file.write_all(record.format(self.format).as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record() -> Record {
+        Record {
+            time: UNIX_EPOCH + Duration::from_secs(1_529_327_106),
+            peer: "127.0.0.1:51234".parse().unwrap(),
+            selector: Some("/search".to_string()),
+            search: Some("\"rust\"".to_string()),
+            gtype: Some(GopherType::Directory),
+            bytes: 1234,
+            duration: Duration::from_millis(15),
+            status: Status::Ok,
+        }
+    }
+
+    #[test]
+    fn test_format_common() {
+        assert_eq!(
+            record().format(Format::Common),
+            "127.0.0.1 - - [18/Jun/2018:13:05:06 +0000] \"/search\" \
+             \"\\\"rust\\\"\" 1 ok 1234 15\n"
+        );
+        let failed = Record::new("[::1]:70".parse().unwrap());
+        assert!(failed
+            .format(Format::Common)
+            .ends_with("] - - - failed 0 0\n"));
+    }
+
+    #[test]
+    fn test_format_json() {
+        assert_eq!(
+            record().format(Format::Json),
+            "{\"time\":\"2018-06-18T13:05:06Z\",\"peer\":\"127.0.0.1:51234\",\
+             \"selector\":\"/search\",\"search\":\"\\\"rust\\\"\",\
+             \"type\":\"1\",\"status\":\"ok\",\"bytes\":1234,\
+             \"duration_ms\":15}\n"
+        );
+    }
+
+    #[test]
+    fn test_utc() {
+        assert_eq!(utc(UNIX_EPOCH), ((1970, 1, 1), (0, 0, 0)));
+        assert_eq!(
+            utc(UNIX_EPOCH + Duration::from_secs(951_825_599)),
+            ((2000, 2, 29), (11, 59, 59))
+        );
+    }
+}
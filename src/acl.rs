@@ -0,0 +1,279 @@
+/*
+ *    Copyright (C) 2016-2018 Stefan Luecke
+ *                  2018 Nicolas Martin
+ *
+ *    This program is free software: you can redistribute it and/or modify
+ *    it under the terms of the GNU Affero General Public License as published
+ *    by the Free Software Foundation, either version 3 of the License, or
+ *    (at your option) any later version.
+ *
+ *    This program is distributed in the hope that it will be useful,
+ *    but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *    GNU Affero General Public License for more details.
+ *
+ *    You should have received a copy of the GNU Affero General Public License
+ *    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ *    Authors: Stefan Luecke <glaxx@glaxx.net>
+ *             Nicolas Martin <penguwingit@gmail.com>
+ */
+//! Access control by client network and selector.
+use super::std;
+use cidr::Network;
+use content;
+use libc;
+use std::io::{Error, ErrorKind, Read};
+use std::net::IpAddr;
+use toml;
+use {Acl, Config};
+
+/// Name of the file with rules overriding those of the config file for the
+/// directory it is in and everything below.
+pub const OVERRIDE_FILE: &str = ".access";
+
+/// What happens to requests a rule matches.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    Allow,
+    Deny,
+}
+
+/// The contents of an override file.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Override {
+    #[serde(default)]
+    acl: Vec<Acl>,
+}
+
+/// Matches `s` against the glob `pattern`. `*` and `?` match any characters
+/// or a single one except `/`, `**` matches across directories. A trailing
+/// `/**` matches the directory itself as well.
+fn glob(pattern: &str, s: &str) -> bool {
+    if pattern.ends_with("/**") && s == &pattern[..pattern.len() - 3] {
+        return true;
+    }
+    glob_bytes(pattern.as_bytes(), s.as_bytes())
+}
+
+fn glob_bytes(pattern: &[u8], s: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => s.is_empty(),
+        Some((b'*', rest)) if rest.first() == Some(&b'*') => {
+            let rest = &rest[1..];
+            (0..=s.len()).any(|i| glob_bytes(rest, &s[i..]))
+        }
+        Some((b'*', rest)) => {
+            let end = s.iter().position(|&c| c == b'/').unwrap_or(s.len());
+            (0..=end).any(|i| glob_bytes(rest, &s[i..]))
+        }
+        Some((b'?', rest)) => match s.split_first() {
+            Some((&c, s)) if c != b'/' => glob_bytes(rest, s),
+            _ => false,
+        },
+        Some((c, rest)) => match s.split_first() {
+            Some((d, s)) if c == d => glob_bytes(rest, s),
+            _ => false,
+        },
+    }
+}
+
+/// Returns the action of the first of `rules` matching `selector` and
+/// `peer`, if any.
+fn first_match(rules: &[Acl], selector: &str, peer: &IpAddr) -> Option<Action> {
+    rules
+        .iter()
+        .find(|r| {
+            glob(&r.selector, selector)
+                && (r.networks.is_empty()
+                    || Network::any_contains(&r.networks, peer))
+        })
+        .map(|r| r.action)
+}
+
+/// Removes empty and `.` components from `selector`, so equivalent
+/// selectors are matched the same way.
+fn normalize(selector: &str) -> String {
+    selector
+        .split('/')
+        .filter(|c| !c.is_empty() && *c != ".")
+        .fold(String::new(), |res, c| res + "/" + c)
+}
+
+/// Reads the rules of the override file in the directory `dir`, if any.
+fn read_override(config: &Config, dir: &str) -> Result<Vec<Acl>, Error> {
+    let path = format!("{}{}/{}", config.general.rootdir, dir, OVERRIDE_FILE);
+    let mut content = String::new();
+    match std::fs::File::open(&path) {
+        Ok(mut file) => file.read_to_string(&mut content)?,
+        Err(ref e)
+            if e.kind() == ErrorKind::NotFound
+                || e.raw_os_error() == Some(libc::ENOTDIR) =>
+        {
+            return Ok(Vec::new())
+        }
+        Err(e) => return Err(e),
+    };
+    let parsed: Override = toml::from_str(&content).map_err(|e| {
+        Error::new(ErrorKind::InvalidData, format!("{}: {}", path, e))
+    })?;
+    Ok(parsed.acl)
+}
+
+/// Decides whether `peer` may request `selector`. If it leads through a
+/// symbolic link, the path it resolves to has to be allowed as well, so links
+/// don't bypass the rules of their target.
+///
+/// # Arguments
+///
+/// * `config` - The server configuration.
+/// * `selector` - The requested selector.
+/// * `peer` - The address of the client.
+pub(crate) fn check(
+    config: &Config,
+    selector: &str,
+    peer: &IpAddr,
+) -> Result<Action, Error> {
+    if check_selector(config, selector, peer)? == Action::Deny {
+        return Ok(Action::Deny);
+    }
+    // Selectors which aren't files, like generated pages, resolve to nothing
+    match content::local_path(config, selector) {
+        Ok((_, ref resolved)) if normalize(resolved) != normalize(selector) => {
+            check_selector(config, resolved, peer)
+        }
+        _ => Ok(Action::Allow),
+    }
+}
+
+/// Decides whether `peer` may request `selector` as it is. The override
+/// files are consulted first, starting with the one closest to the selector,
+/// then the rules of the config file. The first matching rule wins, requests
+/// no rule matches are allowed.
+fn check_selector(
+    config: &Config,
+    selector: &str,
+    peer: &IpAddr,
+) -> Result<Action, Error> {
+    let selector = normalize(selector);
+    let mut dirs: Vec<&str> = selector
+        .match_indices('/')
+        .map(|(i, _)| &selector[..i])
+        .collect();
+    dirs.push(&selector);
+    for dir in dirs.into_iter().rev() {
+        let rules = read_override(config, dir)?;
+        let relative = selector[dir.len()..].trim_start_matches('/');
+        if let Some(action) = first_match(&rules, relative, peer) {
+            return Ok(action);
+        }
+    }
+    Ok(first_match(&config.acl, &selector, peer).unwrap_or(Action::Allow))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_glob() {
+        assert!(glob("/internal/**", "/internal/a/b.txt"));
+        assert!(glob("/internal/**", "/internal"));
+        assert!(!glob("/internal/**", "/internals"));
+        assert!(glob("/docs/*.md", "/docs/readme.md"));
+        assert!(!glob("/docs/*.md", "/docs/old/readme.md"));
+        assert!(glob("/docs/**.md", "/docs/old/readme.md"));
+        assert!(glob("/file?.txt", "/file1.txt"));
+        assert!(!glob("/file?.txt", "/file10.txt"));
+        assert!(glob("**", ""));
+    }
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(normalize("//internal/./secret/"), "/internal/secret");
+        assert_eq!(normalize("/"), "");
+    }
+
+    #[test]
+    fn test_check() {
+        let root = std::env::temp_dir().join("rusty-gopher-acl-test");
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(root.join("internal/public")).unwrap();
+        std::fs::write(
+            root.join("internal/public").join(OVERRIDE_FILE),
+            "[[acl]]\naction = \"allow\"\n",
+        )
+        .unwrap();
+        let mut config = Config::default();
+        config.general.rootdir = root.to_str().unwrap().to_string();
+        config.acl = toml::from_str::<Override>(
+            "[[acl]]\n\
+             action = \"allow\"\n\
+             selector = \"/internal/**\"\n\
+             networks = [\"10.0.0.0/8\"]\n\
+             [[acl]]\n\
+             action = \"deny\"\n\
+             selector = \"/internal/**\"\n",
+        )
+        .unwrap()
+        .acl;
+
+        let lan: IpAddr = "10.1.2.3".parse().unwrap();
+        let other: IpAddr = "192.0.2.1".parse().unwrap();
+        let check = |selector, peer| check(&config, selector, peer).unwrap();
+        assert_eq!(check("/internal/x.txt", &lan), Action::Allow);
+        assert_eq!(check("/internal/x.txt", &other), Action::Deny);
+        assert_eq!(check("//internal/", &other), Action::Deny);
+        assert_eq!(check("/internal/public/x.txt", &other), Action::Allow);
+        assert_eq!(check("/", &other), Action::Allow);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_check_symlink() {
+        let root = std::env::temp_dir().join("rusty-gopher-acl-link-test");
+        let _ = std::fs::remove_dir_all(&root);
+        for dir in &["internal", "private", "public"] {
+            std::fs::create_dir_all(root.join(dir)).unwrap();
+        }
+        std::fs::write(root.join("internal/secret.txt"), "secret").unwrap();
+        std::fs::write(root.join("private/diary.txt"), "diary").unwrap();
+        std::fs::write(
+            root.join("private").join(OVERRIDE_FILE),
+            "[[acl]]\naction = \"deny\"\n",
+        )
+        .unwrap();
+        std::fs::write(root.join("public/hello.txt"), "hello").unwrap();
+        std::os::unix::fs::symlink(
+            root.join("internal"),
+            root.join("public/link"),
+        )
+        .unwrap();
+        std::os::unix::fs::symlink(
+            root.join("private/diary.txt"),
+            root.join("public/diary.txt"),
+        )
+        .unwrap();
+        let mut config = Config::default();
+        config.general.rootdir = root.to_str().unwrap().to_string();
+        config.acl = toml::from_str::<Override>(
+            "[[acl]]\n\
+             action = \"deny\"\n\
+             selector = \"/internal/**\"\n",
+        )
+        .unwrap()
+        .acl;
+
+        let peer: IpAddr = "192.0.2.1".parse().unwrap();
+        let check = |selector| check(&config, selector, &peer).unwrap();
+        assert_eq!(check("/internal/secret.txt"), Action::Deny);
+        assert_eq!(check("/public/link/secret.txt"), Action::Deny);
+        assert_eq!(check("/public/link"), Action::Deny);
+        assert_eq!(check("/public/diary.txt"), Action::Deny);
+        assert_eq!(check("/public/hello.txt"), Action::Allow);
+        assert_eq!(check("/public/missing.txt"), Action::Allow);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
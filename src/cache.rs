@@ -0,0 +1,252 @@
+/*
+ *    Copyright (C) 2016-2018 Stefan Luecke
+ *                  2018 Nicolas Martin
+ *
+ *    This program is free software: you can redistribute it and/or modify
+ *    it under the terms of the GNU Affero General Public License as published
+ *    by the Free Software Foundation, either version 3 of the License, or
+ *    (at your option) any later version.
+ *
+ *    This program is distributed in the hope that it will be useful,
+ *    but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *    GNU Affero General Public License for more details.
+ *
+ *    You should have received a copy of the GNU Affero General Public License
+ *    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ *    Authors: Stefan Luecke <glaxx@glaxx.net>
+ *             Nicolas Martin <penguwingit@gmail.com>
+ */
+//! Bounded in-memory cache of menus and files, invalidated by mtime.
+use content::Content;
+use std::collections::HashMap;
+use std::io::Error;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+use std::time::SystemTime;
+use Cache as Limits;
+
+/// A cached item.
+struct Entry {
+    content: Content,
+    /// Modification time of the path the content was loaded from.
+    modified: SystemTime,
+    /// Size of the content in bytes.
+    size: usize,
+    /// Value of the use counter when the entry was last used.
+    used: u64,
+}
+
+#[derive(Default)]
+struct State {
+    entries: HashMap<PathBuf, Entry>,
+    /// Size of all cached items in bytes.
+    size: usize,
+    /// Incremented on every use, to find the least recently used entry.
+    uses: u64,
+    hits: u64,
+    misses: u64,
+}
+
+/// Usage statistics of a cache.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Stats {
+    pub entries: usize,
+    /// Size of all cached items in bytes.
+    pub size: usize,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+/// Cache of the content of paths below the data root directory.
+#[derive(Default)]
+pub struct Cache {
+    state: Mutex<State>,
+}
+
+impl Cache {
+    pub fn new() -> Cache {
+        Cache::default()
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().expect("cache lock poisoned")
+    }
+
+    /// Returns the content of `path`, from the cache if it hasn't been
+    /// modified since it was cached. Otherwise the content is loaded and
+    /// cached if it fits the `limits`.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The file or directory the content is generated from.
+    /// * `limits` - The cache section of the config.
+    /// * `load` - Generates the content.
+    pub(crate) fn get<F>(
+        &self,
+        path: &Path,
+        limits: &Limits,
+        load: F,
+    ) -> Result<Content, Error>
+    where
+        F: FnOnce() -> Result<Content, Error>,
+    {
+        let modified = match path.metadata().and_then(|m| m.modified()) {
+            Ok(modified) if limits.size > 0 => modified,
+            _ => return load(),
+        };
+        {
+            let mut state = self.state();
+            state.uses += 1;
+            let uses = state.uses;
+            let hit = match state.entries.get_mut(path) {
+                Some(ref mut entry) if entry.modified == modified => {
+                    entry.used = uses;
+                    Some(entry.content.clone())
+                }
+                _ => None,
+            };
+            match hit {
+                Some(content) => {
+                    state.hits += 1;
+                    return Ok(content);
+                }
+                None => state.misses += 1,
+            }
+        }
+
+        let content = load()?;
+        let size = match content {
+            Content::Menu(ref map) => map.to_string().len(),
+            Content::Document(_, ref data) => data.len(),
+            Content::File(_, ref path) => path.as_os_str().len(),
+        };
+        let mut state = self.state();
+        if let Some(old) = state.entries.remove(path) {
+            state.size -= old.size;
+        }
+        if size <= limits.maxitem && size <= limits.size {
+            while state.size + size > limits.size {
+                state.evict();
+            }
+            state.size += size;
+            let used = state.uses;
+            state.entries.insert(
+                path.to_path_buf(),
+                Entry {
+                    content: content.clone(),
+                    modified: modified,
+                    size: size,
+                    used: used,
+                },
+            );
+        }
+        Ok(content)
+    }
+
+    /// Returns the usage statistics.
+    pub fn stats(&self) -> Stats {
+        let state = self.state();
+        Stats {
+            entries: state.entries.len(),
+            size: state.size,
+            hits: state.hits,
+            misses: state.misses,
+        }
+    }
+}
+
+impl State {
+    /// Removes the least recently used entry.
+    fn evict(&mut self) {
+        let path = self
+            .entries
+            .iter()
+            .min_by_key(|&(_, e)| e.used)
+            .map(|(path, _)| path.clone());
+        if let Some(entry) = path.and_then(|p| self.entries.remove(&p)) {
+            self.size -= entry.size;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gophertype::GopherType;
+    use std::time::Duration;
+    use toml;
+
+    fn document(data: &str) -> Result<Content, Error> {
+        Ok(Content::Document(
+            GopherType::File,
+            data.as_bytes().to_vec(),
+        ))
+    }
+
+    fn data(content: Content) -> Vec<u8> {
+        content.into_response().data
+    }
+
+    #[test]
+    fn test_get() {
+        let dir = std::env::temp_dir().join("rusty-gopher-cache-test");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = |name: &str| dir.join(name);
+        for name in &["a", "b", "c"] {
+            std::fs::write(path(name), name).unwrap();
+        }
+        let limits: Limits =
+            toml::from_str("size = 10\nmaxitem = 4\n").unwrap();
+        let cache = Cache::new();
+
+        assert_eq!(
+            data(cache.get(&path("a"), &limits, || document("aaa")).unwrap()),
+            b"aaa"
+        );
+        assert_eq!(
+            data(cache.get(&path("a"), &limits, || document("new")).unwrap()),
+            b"aaa"
+        );
+        assert_eq!(
+            data(
+                cache
+                    .get(&path("b"), &limits, || document("bbbbb"))
+                    .unwrap()
+            ),
+            b"bbbbb"
+        );
+        assert_eq!(
+            cache.stats(),
+            Stats {
+                entries: 1,
+                size: 3,
+                hits: 1,
+                misses: 2,
+            }
+        );
+
+        // Evicts the least recently used entry
+        cache.get(&path("b"), &limits, || document("bbbb")).unwrap();
+        cache.get(&path("a"), &limits, || document("new")).unwrap();
+        cache.get(&path("c"), &limits, || document("cccc")).unwrap();
+        let stats = cache.stats();
+        assert_eq!((stats.entries, stats.size), (2, 7));
+        assert_eq!(
+            data(cache.get(&path("a"), &limits, || document("new")).unwrap()),
+            b"aaa"
+        );
+
+        // Reloads modified files
+        std::thread::sleep(Duration::from_millis(20));
+        std::fs::write(path("a"), "A").unwrap();
+        assert_eq!(
+            data(cache.get(&path("a"), &limits, || document("new")).unwrap()),
+            b"new"
+        );
+        assert_eq!(cache.stats().size, 7);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
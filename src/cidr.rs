@@ -0,0 +1,206 @@
+/*
+ *    Copyright (C) 2016-2018 Stefan Luecke
+ *                  2018 Nicolas Martin
+ *
+ *    This program is free software: you can redistribute it and/or modify
+ *    it under the terms of the GNU Affero General Public License as published
+ *    by the Free Software Foundation, either version 3 of the License, or
+ *    (at your option) any later version.
+ *
+ *    This program is distributed in the hope that it will be useful,
+ *    but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *    GNU Affero General Public License for more details.
+ *
+ *    You should have received a copy of the GNU Affero General Public License
+ *    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ *    Authors: Stefan Luecke <glaxx@glaxx.net>
+ *             Nicolas Martin <penguwingit@gmail.com>
+ */
+//! IP networks in CIDR notation, used to restrict access by client address.
+use super::std;
+use serde::de::{self, Deserialize, Deserializer};
+use serde::{Serialize, Serializer};
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// An IPv4 or IPv6 network like `192.168.0.0/16`. A plain address is a
+/// network containing only itself.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Network {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+/// Returns `addr` as IPv4 address if it is an IPv4 address mapped to IPv6.
+pub fn canonical(addr: &IpAddr) -> IpAddr {
+    match *addr {
+        IpAddr::V6(ref v6) => match v6.to_ipv4() {
+            Some(v4) if v6.segments()[..6] == [0, 0, 0, 0, 0, 0xffff] => {
+                IpAddr::V4(v4)
+            }
+            _ => *addr,
+        },
+        IpAddr::V4(_) => *addr,
+    }
+}
+
+impl Network {
+    /// Returns the network with the given `prefix` length `addr` is part of.
+    /// The prefix is shortened to the length of the address if necessary.
+    pub fn containing(addr: &IpAddr, prefix: u8) -> Network {
+        match canonical(addr) {
+            IpAddr::V4(v4) => {
+                let prefix = prefix.min(32);
+                let net = u32::from(v4) & mask(prefix, 32) as u32;
+                Network {
+                    addr: IpAddr::V4(net.into()),
+                    prefix: prefix,
+                }
+            }
+            IpAddr::V6(v6) => {
+                let prefix = prefix.min(128);
+                let net = u128::from(v6) & mask(prefix, 128);
+                Network {
+                    addr: IpAddr::V6(net.into()),
+                    prefix: prefix,
+                }
+            }
+        }
+    }
+
+    /// Checks whether `addr` is part of the network. IPv4 addresses mapped
+    /// to IPv6 match IPv4 networks.
+    pub fn contains(&self, addr: &IpAddr) -> bool {
+        match (self.addr, canonical(addr)) {
+            (IpAddr::V4(net), IpAddr::V4(addr)) => {
+                let mask = mask(self.prefix, 32) as u32;
+                u32::from(net) & mask == u32::from(addr) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(addr)) => {
+                let mask = mask(self.prefix, 128);
+                u128::from(net) & mask == u128::from(addr) & mask
+            }
+            _ => false,
+        }
+    }
+
+    /// Checks whether any of `networks` contains `addr`.
+    pub fn any_contains(networks: &[Network], addr: &IpAddr) -> bool {
+        networks.iter().any(|n| n.contains(addr))
+    }
+}
+
+/// Returns a mask with the upper `prefix` of `bits` bits set.
+fn mask(prefix: u8, bits: u8) -> u128 {
+    if prefix == 0 {
+        0
+    } else {
+        (!0u128 << (bits - prefix)) & (!0u128 >> (128 - bits))
+    }
+}
+
+impl FromStr for Network {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Network, String> {
+        let mut parts = s.splitn(2, '/');
+        let addr = IpAddr::from_str(parts.next().unwrap_or(""))
+            .map_err(|e| format!("invalid network \"{}\": {}", s, e))?;
+        let bits = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match parts.next() {
+            Some(prefix) => match prefix.parse::<u8>() {
+                Ok(prefix) if prefix <= bits => prefix,
+                _ => {
+                    return Err(format!(
+                        "invalid prefix length in \"{}\", must be 0 to {}",
+                        s, bits
+                    ))
+                }
+            },
+            None => bits,
+        };
+        Ok(Network {
+            addr: addr,
+            prefix: prefix,
+        })
+    }
+}
+
+impl std::fmt::Display for Network {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+impl Serialize for Network {
+    fn serialize<S: Serializer>(
+        &self,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Network {
+    fn deserialize<D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Network, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_contains() {
+        let lan: Network = "192.168.0.0/16".parse().unwrap();
+        assert!(lan.contains(&ip("192.168.42.1")));
+        assert!(lan.contains(&ip("::ffff:192.168.42.1")));
+        assert!(!lan.contains(&ip("192.169.0.1")));
+        assert!(!lan.contains(&ip("::1")));
+
+        let host: Network = "::1".parse().unwrap();
+        assert!(host.contains(&ip("::1")));
+        assert!(!host.contains(&ip("::2")));
+
+        let all: Network = "0.0.0.0/0".parse().unwrap();
+        assert!(all.contains(&ip("8.8.8.8")));
+        let docs: Network = "2001:db8::/32".parse().unwrap();
+        assert!(docs.contains(&ip("2001:db8:1::1")));
+        assert!(!docs.contains(&ip("2001:db9::1")));
+    }
+
+    #[test]
+    fn test_containing() {
+        let net = Network::containing(&ip("::ffff:192.168.42.1"), 24);
+        assert_eq!(net.to_string(), "192.168.42.0/24");
+        assert_eq!(net, Network::containing(&ip("192.168.42.200"), 24));
+        assert_eq!(
+            Network::containing(&ip("2001:db8:1:2:3::1"), 64).to_string(),
+            "2001:db8:1:2::/64"
+        );
+        assert_eq!(
+            Network::containing(&ip("10.1.2.3"), 64).to_string(),
+            "10.1.2.3/32"
+        );
+    }
+
+    #[test]
+    fn test_from_str() {
+        assert_eq!(
+            "10.0.0.1".parse::<Network>().unwrap().to_string(),
+            "10.0.0.1/32"
+        );
+        assert!("10.0.0.0/33".parse::<Network>().is_err());
+        assert!("localhost".parse::<Network>().is_err());
+    }
+}
@@ -0,0 +1,327 @@
+/*
+ *    Copyright (C) 2016-2018 Stefan Luecke
+ *                  2018 Nicolas Martin
+ *
+ *    This program is free software: you can redistribute it and/or modify
+ *    it under the terms of the GNU Affero General Public License as published
+ *    by the Free Software Foundation, either version 3 of the License, or
+ *    (at your option) any later version.
+ *
+ *    This program is distributed in the hope that it will be useful,
+ *    but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *    GNU Affero General Public License for more details.
+ *
+ *    You should have received a copy of the GNU Affero General Public License
+ *    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ *    Authors: Stefan Luecke <glaxx@glaxx.net>
+ *             Nicolas Martin <penguwingit@gmail.com>
+ */
+//! Gopher client, fetching menus and documents from other servers.
+use directoryentry::DirectoryEntry;
+use gophermap::{Gophermap, Mode, Parser};
+use gophertype::GopherType;
+use gopherurl::GopherUrl;
+use std::io::{Error, ErrorKind};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::time::Duration;
+use tokio;
+use tokio::net::TcpStream;
+use tokio::prelude::*;
+
+/// Builds the request line for `url`, including its search string.
+fn request_line(url: &GopherUrl) -> Vec<u8> {
+    match url.search {
+        Some(ref search) => format!("{}\t{}\r\n", url.selector, search),
+        None => format!("{}\r\n", url.selector),
+    }
+    .into_bytes()
+}
+
+/// Turns the timeout of a request into an `io::Error`.
+fn timeout_error(e: tokio::timer::timeout::Error<Error>) -> Error {
+    e.into_inner()
+        .unwrap_or_else(|| Error::new(ErrorKind::TimedOut, "request timed out"))
+}
+
+/// Looks up the address of the server of `url`. Host names are resolved
+/// synchronously, so this has to happen before building request futures.
+pub fn resolve(url: &GopherUrl) -> Result<SocketAddr, Error> {
+    (url.host.as_str(), url.port)
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| Error::new(ErrorKind::NotFound, "host has no address"))
+}
+
+/// Connects to the server of `url` and sends the request. The returned
+/// stream yields the response.
+///
+/// # Arguments
+///
+/// * `url` - The item to request.
+/// * `addr` - The address of its server, see `resolve`.
+/// * `timeout` - Time allowed for connecting and sending the request.
+pub fn request(
+    url: &GopherUrl,
+    addr: &SocketAddr,
+    timeout: Duration,
+) -> impl Future<Item = TcpStream, Error = Error> {
+    let line = request_line(url);
+    TcpStream::connect(addr)
+        .and_then(move |stream| tokio::io::write_all(stream, line))
+        .map(|(stream, _)| stream)
+        .timeout(timeout)
+        .map_err(timeout_error)
+}
+
+/// Fetches the raw response to `url`.
+///
+/// # Arguments
+///
+/// * `url` - The item to request.
+/// * `addr` - The address of its server, see `resolve`.
+/// * `timeout` - Time allowed for the whole transfer.
+///
+/// # Examples
+///
+/// ```
+/// let url = GopherUrl::parse("gopher://gopher.floodgap.com/0/gopher/proxy")
+///     .unwrap();
+/// let addr = client::resolve(&url).unwrap();
+/// let f = client::fetch(&url, &addr, Duration::from_secs(30));
+/// ```
+pub fn fetch(
+    url: &GopherUrl,
+    addr: &SocketAddr,
+    timeout: Duration,
+) -> impl Future<Item = Vec<u8>, Error = Error> {
+    request(url, addr, timeout)
+        .and_then(|stream| tokio::io::read_to_end(stream, Vec::new()))
+        .map(|(_, data)| data)
+        .timeout(timeout)
+        .map_err(timeout_error)
+}
+
+/// Fetches and parses the menu `url` points to.
+///
+/// # Arguments
+///
+/// * `url` - The menu to request.
+/// * `addr` - The address of its server, see `resolve`.
+/// * `timeout` - Time allowed for the whole transfer.
+pub fn fetch_menu(
+    url: &GopherUrl,
+    addr: &SocketAddr,
+    timeout: Duration,
+) -> impl Future<Item = Gophermap, Error = Error> {
+    let (host, port) = (url.host.clone(), url.port);
+    fetch(url, addr, timeout).map(move |data| parse_menu(&data, host, port))
+}
+
+/// Fetches the text document `url` points to, removing the terminator.
+///
+/// # Arguments
+///
+/// * `url` - The document to request.
+/// * `addr` - The address of its server, see `resolve`.
+/// * `timeout` - Time allowed for the whole transfer.
+pub fn fetch_text(
+    url: &GopherUrl,
+    addr: &SocketAddr,
+    timeout: Duration,
+) -> impl Future<Item = String, Error = Error> {
+    fetch(url, addr, timeout).map(|data| strip_terminator(&data))
+}
+
+/// Parses a menu response of the server at `host` and `port`, which fill in
+/// missing fields. Parsing stops at the `.` terminator, lines which are not
+/// valid menu entries are turned into errors describing the problem.
+pub fn parse_menu(data: &[u8], host: String, port: u16) -> Gophermap {
+    Gophermap {
+        entries: Parser::new(
+            data,
+            Mode::Lenient {
+                host: host,
+                port: port,
+            },
+        )
+        .map(|e| e.unwrap_or_else(|e| DirectoryEntry::error(e.to_string())))
+        .collect(),
+    }
+}
+
+/// Turns a text response into its content. Everything from the `.`
+/// terminator on is removed and lines escaped with a leading `..` are
+/// restored.
+pub fn strip_terminator(data: &[u8]) -> String {
+    let mut text = String::new();
+    for line in String::from_utf8_lossy(data).lines() {
+        let line = line.trim_end_matches('\r');
+        if line == "." {
+            break;
+        }
+        if line.starts_with("..") {
+            text.push_str(&line[1..]);
+        } else {
+            text.push_str(line);
+        }
+        text.push('\n');
+    }
+    text
+}
+
+/// Renders a menu as a table for terminals. Entries are numbered and
+/// followed by the URL they point to.
+///
+/// # Examples
+///
+/// ```
+/// print!("{}", client::format_menu(&map));
+/// ```
+pub fn format_menu(map: &Gophermap) -> String {
+    let width = map
+        .entries
+        .iter()
+        .map(|e| e.description.chars().count())
+        .max()
+        .unwrap_or(0);
+    let mut res = String::new();
+    let mut number = 0;
+    for entry in &map.entries {
+        let label = match entry.gtype {
+            GopherType::Informational => {
+                res.push_str(&format!("           {}\n", entry.description));
+                continue;
+            }
+            GopherType::Gif => "GIF",
+            GopherType::Directory => "DIR",
+            GopherType::File => "TXT",
+            GopherType::BinaryFile => "BIN",
+            GopherType::Error => "ERR",
+            GopherType::Html => "HTML",
+            GopherType::CcsoNameServer => "CSO",
+            GopherType::BinHex => "HQX",
+            GopherType::DosFile => "DOS",
+            GopherType::UuEncoded => "UUE",
+            GopherType::Search => "SRCH",
+            GopherType::Telnet => "TEL",
+            GopherType::Mirror => "MIRR",
+            GopherType::Tn3270 => "3270",
+            GopherType::Sound => "SND",
+            GopherType::Image => "IMG",
+            GopherType::Other(_) => "?",
+        };
+        let target = match entry.url_target() {
+            Some(url) => url.to_string(),
+            None if entry.gtype == GopherType::Error => String::new(),
+            None => GopherUrl::from_entry(entry).to_string(),
+        };
+        number += 1;
+        res.push_str(&format!(
+            "{:>4} {:<5} {:<width$}  {}\n",
+            number,
+            label,
+            entry.description,
+            target,
+            width = width
+        ));
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    #[test]
+    fn test_parse_menu() {
+        let map = parse_menu(
+            b"iWelcome\t\tnull.host\t1\r\n\
+              1Phlog\t/phlog\tlocalhost\t70\r\n\
+              plain text\r\n\
+              0About\t/about.txt\r\n\
+              1Broken\t/broken\tlocalhost\tport\r\n\
+              .\r\n\
+              0Ignored\t/x\tlocalhost\t70\r\n",
+            "example.org".to_string(),
+            7070,
+        );
+        assert_eq!(map.entries.len(), 5);
+        assert_eq!(map.entries[1].gtype, GopherType::Directory);
+        assert_eq!(map.entries[1].selector, "/phlog");
+        assert_eq!(map.entries[2].gtype, GopherType::Informational);
+        assert_eq!(map.entries[2].description, "plain text");
+        assert_eq!(map.entries[3].host, "example.org");
+        assert_eq!(map.entries[3].port, 7070);
+        assert_eq!(map.entries[4].gtype, GopherType::Error);
+        assert_eq!(
+            map.entries[4].description,
+            "line 5, column 27: invalid port \"port\""
+        );
+    }
+
+    #[test]
+    fn test_strip_terminator() {
+        assert_eq!(
+            strip_terminator(b"Hello\r\n..dot\r\n.\r\nignored\r\n"),
+            "Hello\n.dot\n"
+        );
+        assert_eq!(strip_terminator(b"No terminator\n"), "No terminator\n");
+    }
+
+    #[test]
+    fn test_format_menu() {
+        let map = parse_menu(
+            b"iWelcome\t\tnull.host\t1\r\n\
+              1Phlog\t/phlog\tlocalhost\t70\r\n\
+              hRust\tURL:https://www.rust-lang.org/\tlocalhost\t70\r\n",
+            "localhost".to_string(),
+            70,
+        );
+        assert_eq!(
+            format_menu(&map),
+            "           Welcome\n   \
+             1 DIR   Phlog    gopher://localhost:70/1/phlog\n   \
+             2 HTML  Rust     https://www.rust-lang.org/\n"
+        );
+    }
+
+    #[test]
+    fn test_fetch_menu() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut line = String::new();
+            BufReader::new(&stream).read_line(&mut line).unwrap();
+            (&stream)
+                .write_all(
+                    format!(
+                        "iGot {}\t\tnull.host\t1\r\n.\r\n",
+                        line.trim().replace('\t', " ")
+                    )
+                    .as_bytes(),
+                )
+                .unwrap();
+            line
+        });
+
+        let url = GopherUrl::parse(&format!(
+            "gopher://127.0.0.1:{}/7/search%09rust",
+            port
+        ))
+        .unwrap();
+        let addr = resolve(&url).unwrap();
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        let map = rt
+            .block_on(fetch_menu(&url, &addr, Duration::from_secs(5)))
+            .unwrap();
+        assert_eq!(server.join().unwrap(), "/search\trust\r\n");
+        assert_eq!(map.entries.len(), 1);
+        assert_eq!(map.entries[0].description, "Got /search rust");
+    }
+}
@@ -0,0 +1,528 @@
+/*
+ *    Copyright (C) 2016-2018 Stefan Luecke
+ *                  2018 Nicolas Martin
+ *
+ *    This program is free software: you can redistribute it and/or modify
+ *    it under the terms of the GNU Affero General Public License as published
+ *    by the Free Software Foundation, either version 3 of the License, or
+ *    (at your option) any later version.
+ *
+ *    This program is distributed in the hope that it will be useful,
+ *    but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *    GNU Affero General Public License for more details.
+ *
+ *    You should have received a copy of the GNU Affero General Public License
+ *    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ *    Authors: Stefan Luecke <glaxx@glaxx.net>
+ *             Nicolas Martin <penguwingit@gmail.com>
+ */
+use super::std;
+use acl;
+use crawlers;
+use directoryentry::DirectoryEntry;
+use gophermap::Gophermap;
+use gophertype::GopherType;
+use hostname::get_hostname;
+use markdown;
+use phlog;
+use std::io::{Error, ErrorKind, Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, Instant};
+use tokio;
+use tokio::prelude::future::Either;
+use tokio::prelude::*;
+use tokio::timer::{timeout, Delay};
+use {Config, Phlog};
+
+/// The content a selector resolves to, independent of the protocol it is
+/// served with.
+#[derive(Debug, Clone)]
+pub enum Content {
+    /// A menu.
+    Menu(Gophermap),
+    /// A document of the given type.
+    Document(GopherType, Vec<u8>),
+    /// A file of the given type, too large to be read into memory. It is
+    /// sent from disk.
+    File(GopherType, PathBuf),
+}
+
+impl Content {
+    /// Returns the item type of the content.
+    pub fn gtype(&self) -> GopherType {
+        match *self {
+            Content::Menu(_) => GopherType::Directory,
+            Content::Document(ref gtype, _) | Content::File(ref gtype, _) => {
+                gtype.clone()
+            }
+        }
+    }
+
+    /// Returns the content the way it is sent to gopher clients.
+    pub fn into_response(self) -> Response {
+        match self {
+            Content::Menu(map) => map.to_string().into_bytes().into(),
+            Content::Document(_, data) => data.into(),
+            Content::File(_, path) => Response {
+                data: Vec::new(),
+                file: Some(path),
+            },
+        }
+    }
+}
+
+/// The bytes sent to a client, followed by the contents of a file.
+#[derive(Debug)]
+pub struct Response {
+    pub data: Vec<u8>,
+    pub file: Option<PathBuf>,
+}
+
+impl From<Vec<u8>> for Response {
+    fn from(data: Vec<u8>) -> Response {
+        Response {
+            data: data,
+            file: None,
+        }
+    }
+}
+
+impl Response {
+    /// Writes the response to `writer`, copying the file in chunks. Resolves
+    /// to the writer and the number of bytes sent. Fails with `TimedOut` if
+    /// the client doesn't take any data for `idle`, however long the transfer
+    /// takes as a whole.
+    pub fn send<W>(
+        self,
+        writer: W,
+        idle: Duration,
+    ) -> impl Future<Item = (W, u64), Error = Error>
+    where
+        W: AsyncWrite,
+    {
+        let sent = self.data.len() as u64;
+        let file = self.file;
+        let writer = IdleWriter::new(writer, idle);
+        tokio::io::write_all(writer, self.data)
+            .and_then(move |(writer, _)| match file {
+                Some(path) => Either::A(
+                    tokio::fs::File::open(path)
+                        .and_then(|file| tokio::io::copy(file, writer))
+                        .map(move |(copied, _, writer)| {
+                            (writer, sent + copied)
+                        }),
+                ),
+                None => Either::B(future::ok((writer, sent))),
+            })
+            .map(|(writer, sent)| (writer.inner, sent))
+    }
+}
+
+/// Turns the error of reading a request within a time limit into an I/O
+/// error, `TimedOut` if the time ran out.
+pub fn timed_out(e: timeout::Error<Error>) -> Error {
+    if e.is_inner() {
+        return e.into_inner().expect("inner error");
+    }
+    if e.is_elapsed() {
+        return Error::new(ErrorKind::TimedOut, "request timed out");
+    }
+    Error::new(ErrorKind::Other, e)
+}
+
+/// A writer giving up once its peer hasn't taken any data for a while.
+struct IdleWriter<W> {
+    inner: W,
+    idle: Duration,
+    deadline: Delay,
+}
+
+impl<W> IdleWriter<W> {
+    fn new(inner: W, idle: Duration) -> IdleWriter<W> {
+        IdleWriter {
+            inner: inner,
+            idle: idle,
+            deadline: Delay::new(Instant::now() + idle),
+        }
+    }
+
+    /// Turns `WouldBlock` into `TimedOut` once the deadline has passed.
+    fn check(&mut self, e: Error) -> Error {
+        if e.kind() != ErrorKind::WouldBlock {
+            return e;
+        }
+        match self.deadline.poll() {
+            Ok(Async::NotReady) => e,
+            Ok(Async::Ready(())) => {
+                Error::new(ErrorKind::TimedOut, "client stopped reading")
+            }
+            Err(e) => Error::new(ErrorKind::Other, e),
+        }
+    }
+}
+
+impl<W: Write> Write for IdleWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        match self.inner.write(buf) {
+            Ok(n) => {
+                self.deadline.reset(Instant::now() + self.idle);
+                Ok(n)
+            }
+            Err(e) => Err(self.check(e)),
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        self.inner.flush().map_err(|e| self.check(e))
+    }
+}
+
+impl<W: AsyncWrite> AsyncWrite for IdleWriter<W> {
+    fn shutdown(&mut self) -> Poll<(), Error> {
+        self.inner.shutdown()
+    }
+}
+
+/// Returns the path within the data root directory `selector` points to,
+/// along with the selector leading there without detours. Paths outside of
+/// the data root directory, e.g. reached through a symbolic link, and access
+/// rules are reported as not found.
+pub(crate) fn local_path(
+    config: &Config,
+    selector: &str,
+) -> Result<(PathBuf, String), Error> {
+    let not_found = || Error::new(ErrorKind::NotFound, "no such selector");
+    let root = Path::new(&config.general.rootdir).canonicalize()?;
+    let path = root.join(selector.trim_start_matches('/')).canonicalize()?;
+    if path.file_name() == Some(acl::OVERRIDE_FILE.as_ref()) {
+        return Err(not_found());
+    }
+    let selector = match path.strip_prefix(&root).map(|p| p.to_str()) {
+        Ok(Some(relative)) => format!("/{}", relative),
+        _ => return Err(not_found()),
+    };
+    Ok((path, selector))
+}
+
+/// Resolves `selector` against the data root directory.
+///
+/// # Arguments
+///
+/// * `config` - The server configuration.
+/// * `selector` - The requested selector.
+/// * `port` - The port local menu entries point to.
+pub(crate) fn resolve(
+    config: &Config,
+    selector: &str,
+    port: u16,
+) -> Result<Content, Error> {
+    // URLs are not paths, they may contain anything
+    if selector.starts_with("URL:") {
+        return Ok(Content::Document(
+            GopherType::Html,
+            url_redirect(&selector[4..]).into_bytes(),
+        ));
+    }
+
+    // Never leave the data root directory or reveal access rules
+    if Path::new(selector).components().any(|c| {
+        c == Component::ParentDir
+            || c == Component::Normal(acl::OVERRIDE_FILE.as_ref())
+    }) {
+        return Err(Error::new(ErrorKind::NotFound, "no such selector"));
+    }
+
+    if let Some(document) = crawlers::document(config, selector) {
+        return Ok(Content::Document(GopherType::File, document.into_bytes()));
+    }
+
+    if let Some((phlog, request)) = config.phlog_request(selector) {
+        return phlog_page(config, phlog, request, port);
+    }
+
+    let (path, selector) = local_path(config, selector)?;
+    config.contents.get(&path, &config.cache, || {
+        if path.is_file() {
+            file(config, &path, &selector, port)
+        } else {
+            Ok(Content::Menu(Gophermap {
+                entries: directory_listing(config, &path, &selector, port)?,
+            }))
+        }
+    })
+}
+
+/// Generates the customary HTML page redirecting clients which don't know
+/// about `URL:` selectors to `url`.
+fn url_redirect(url: &str) -> String {
+    let url = url
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;");
+    format!(
+        "<HTML>\n<HEAD>\n\
+         <META HTTP-EQUIV=\"refresh\" content=\"2;URL={0}\">\n\
+         </HEAD>\n<BODY>\n\
+         You are following a link from gopher to a web site.  You will be\n\
+         automatically taken to the web site shortly.  If you do not get sent\n\
+         there, please click\n\
+         <A HREF=\"{0}\">here</A> to go to the web site.\n\
+         <P>\nThe URL linked is:\n<P>\n<A HREF=\"{0}\">{0}</A>\n<P>\n\
+         Thanks for using gopher!\n</BODY>\n</HTML>\n",
+        url
+    )
+}
+
+/// Reads the file at `path` that `selector` points to. Markdown files within
+/// the configured directories are rendered.
+fn file(
+    config: &Config,
+    path: &Path,
+    selector: &str,
+    port: u16,
+) -> Result<Content, Error> {
+    let rendering = config.markdown.rendering(selector);
+    if rendering.is_none()
+        && path.metadata()?.len() > config.cache.maxitem as u64
+    {
+        let gtype = file_type(config, selector);
+        return Ok(Content::File(gtype, path.to_path_buf()));
+    }
+    let mut content = Vec::new();
+    std::fs::File::open(path)?.read_to_end(&mut content)?;
+
+    let width = config.markdown.width;
+    match rendering {
+        Some(GopherType::Directory) => {
+            let input = String::from_utf8_lossy(&content);
+            let hostname = get_hostname().expect("Failed to get hostname");
+            Ok(Content::Menu(markdown::to_gophermap(
+                &input,
+                selector,
+                &hostname,
+                port,
+                width,
+                |s| file_type(config, s),
+            )))
+        }
+        Some(gtype) => {
+            let input = String::from_utf8_lossy(&content);
+            Ok(Content::Document(
+                gtype,
+                markdown::to_text(&input, width).into_bytes(),
+            ))
+        }
+        None => Ok(Content::Document(file_type(config, selector), content)),
+    }
+}
+
+/// Determines the gopher type of the file `selector` points to.
+fn file_type(config: &Config, selector: &str) -> GopherType {
+    config.markdown.rendering(selector).unwrap_or_else(|| {
+        match Path::new(selector).extension().and_then(|e| e.to_str()) {
+            Some(ext) => GopherType::from_file_extension(ext),
+            None => GopherType::BinaryFile,
+        }
+    })
+}
+
+/// Generates a page of the phlog engine.
+fn phlog_page(
+    config: &Config,
+    phlog: &Phlog,
+    request: phlog::Request,
+    port: u16,
+) -> Result<Content, Error> {
+    let path = config.general.rootdir.clone() + &phlog.selector;
+    let posts = phlog::read_posts(Path::new(&path), &phlog.selector, |s| {
+        file_type(config, s)
+    })?;
+    let hostname = get_hostname().expect("Failed to get hostname");
+
+    Ok(match request {
+        phlog::Request::Index(page) => Content::Menu(
+            phlog::index(
+                &posts,
+                &phlog.selector,
+                &phlog.title,
+                page,
+                phlog.perpage,
+                &hostname,
+                port,
+            )
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, "no such page"))?,
+        ),
+        phlog::Request::Archive(year, month) => Content::Menu(phlog::archive(
+            &posts,
+            &phlog.selector,
+            year,
+            month,
+            &hostname,
+            port,
+        )),
+        phlog::Request::Feed => Content::Document(
+            GopherType::File,
+            phlog::atom(&posts, &phlog.selector, &phlog.title, &hostname, port)
+                .into_bytes(),
+        ),
+    })
+}
+
+/// Lists the directory at `path` that `selector` points to.
+fn directory_listing(
+    config: &Config,
+    path: &Path,
+    selector: &str,
+    port: u16,
+) -> Result<Vec<DirectoryEntry>, Error> {
+    let rd = std::fs::read_dir(path)?;
+    let hostname = get_hostname().expect("Failed to get hostname");
+    let mut res: Vec<DirectoryEntry> = Vec::new();
+
+    for possible_entry in rd {
+        let entry = possible_entry?;
+        if entry.file_name() == acl::OVERRIDE_FILE {
+            continue;
+        }
+        // Names which aren't valid UTF-8 can't be part of a selector
+        let name = match entry.file_name().into_string() {
+            Ok(name) => name,
+            Err(_) => continue,
+        };
+        // Shouldnt matter assigning GopherType::Error as gtype, after diren only
+        // gets pushed into res when its a directory or file
+        let mut diren = DirectoryEntry {
+            gtype: GopherType::Error,
+            selector: format!("{}/{}", selector.trim_end_matches('/'), name),
+            description: name,
+            host: hostname.clone(),
+            port: port,
+        };
+
+        // If the entry is a directory...
+        if entry.file_type()?.is_dir() {
+            diren.gtype = GopherType::Directory;
+            res.push(diren);
+        } else if entry.file_type()?.is_file() {
+            diren.gtype = file_type(config, &diren.selector);
+            res.push(diren);
+        }
+    }
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_url_redirect() {
+        let page = url_redirect("https://example.org/?a=1&b=\"2\"");
+        assert!(page.contains(
+            "content=\"2;URL=https://example.org/?a=1&amp;b=&quot;2&quot;\""
+        ));
+        assert!(page.contains("Thanks for using gopher!"));
+
+        let config = Config::default();
+        match resolve(&config, "URL:https://example.org/a/../b", 70) {
+            Ok(Content::Document(GopherType::Html, page)) => {
+                let page = String::from_utf8(page).unwrap();
+                assert!(page.contains("URL=https://example.org/a/../b\""));
+            }
+            c => panic!("unexpected {:?}", c),
+        }
+    }
+
+    #[test]
+    fn test_resolve_outside_rootdir() {
+        let dir = std::env::temp_dir().join("rusty-gopher-content-test");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("root/docs")).unwrap();
+        std::fs::create_dir_all(dir.join("root_secret")).unwrap();
+        std::fs::write(dir.join("root/docs/a.txt"), "public").unwrap();
+        std::fs::write(dir.join("root_secret/pw"), "secret").unwrap();
+        std::os::unix::fs::symlink(
+            dir.join("root_secret"),
+            dir.join("root/link"),
+        )
+        .unwrap();
+        let mut config = Config::default();
+        config.general.rootdir = dir.join("root").to_str().unwrap().to_string();
+
+        let data = |selector| {
+            resolve(&config, selector, 70).map(|c| c.into_response().data)
+        };
+        assert_eq!(data("/docs/a.txt").unwrap(), b"public");
+        assert_eq!(data("docs/a.txt").unwrap(), b"public");
+        for selector in &["_secret/pw", "/../root_secret/pw", "/link/pw"] {
+            let e = data(selector).unwrap_err();
+            assert_eq!(e.kind(), ErrorKind::NotFound, "{}", selector);
+        }
+        match resolve(&config, "//docs/", 70).unwrap() {
+            Content::Menu(map) => {
+                assert_eq!(map.entries[0].selector, "/docs/a.txt")
+            }
+            c => panic!("unexpected {:?}", c),
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_resolve_large_file() {
+        let dir = std::env::temp_dir().join("rusty-gopher-large-test");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("small.txt"), "small").unwrap();
+        std::fs::write(dir.join("large.bin"), vec![0u8; 2048]).unwrap();
+        let mut config = Config::default();
+        config.general.rootdir = dir.to_str().unwrap().to_string();
+        config.cache.maxitem = 1024;
+
+        match resolve(&config, "/small.txt", 70).unwrap() {
+            Content::Document(GopherType::File, data) => {
+                assert_eq!(data, b"small")
+            }
+            c => panic!("unexpected {:?}", c),
+        }
+        let response =
+            resolve(&config, "/large.bin", 70).unwrap().into_response();
+        assert!(response.data.is_empty());
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        let writer = std::io::Cursor::new(Vec::new());
+        let idle = Duration::from_secs(30);
+        let (sent, n) = runtime.block_on(response.send(writer, idle)).unwrap();
+        assert_eq!((sent.into_inner().len(), n), (2048, 2048));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// A client that never reads.
+    struct Stalled;
+
+    impl Write for Stalled {
+        fn write(&mut self, _: &[u8]) -> Result<usize, Error> {
+            Err(Error::new(ErrorKind::WouldBlock, "stalled"))
+        }
+
+        fn flush(&mut self) -> Result<(), Error> {
+            Ok(())
+        }
+    }
+
+    impl AsyncWrite for Stalled {
+        fn shutdown(&mut self) -> Poll<(), Error> {
+            Ok(Async::Ready(()))
+        }
+    }
+
+    #[test]
+    fn test_send_idle() {
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        let response = Response::from(b"hello".to_vec());
+        let idle = Duration::from_millis(10);
+        let res = runtime.block_on(response.send(Stalled, idle));
+        assert_eq!(res.err().map(|e| e.kind()), Some(ErrorKind::TimedOut));
+    }
+}
@@ -0,0 +1,129 @@
+/*
+ *    Copyright (C) 2016-2018 Stefan Luecke
+ *                  2018 Nicolas Martin
+ *
+ *    This program is free software: you can redistribute it and/or modify
+ *    it under the terms of the GNU Affero General Public License as published
+ *    by the Free Software Foundation, either version 3 of the License, or
+ *    (at your option) any later version.
+ *
+ *    This program is distributed in the hope that it will be useful,
+ *    but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *    GNU Affero General Public License for more details.
+ *
+ *    You should have received a copy of the GNU Affero General Public License
+ *    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ *    Authors: Stefan Luecke <glaxx@glaxx.net>
+ *             Nicolas Martin <penguwingit@gmail.com>
+ */
+//! robots.txt and caps.txt for gopher crawlers, generated from the config.
+use std::fmt::Write;
+use std::path::Path;
+use {Caps, Config, Robots};
+
+/// Renders the robots.txt excluding the selectors of `robots`.
+fn robots(robots: &Robots) -> String {
+    let mut res = format!("User-agent: {}\n", robots.useragent);
+    if robots.disallow.is_empty() {
+        res.push_str("Disallow:\n");
+    }
+    for selector in &robots.disallow {
+        let _ = writeln!(res, "Disallow: {}", selector);
+    }
+    res
+}
+
+/// Renders the caps.txt describing this server.
+fn caps(caps: &Caps) -> String {
+    let mut res = String::from("CAPS\n\n");
+    let _ = writeln!(res, "CapsVersion=1");
+    let _ = writeln!(res, "ExpireCapsAfter={}", caps.expire);
+    let _ = writeln!(res);
+    // The misspelled "Delimeter" is part of the format
+    let _ = writeln!(res, "PathDelimeter=/");
+    let _ = writeln!(res, "PathIdentity=.");
+    let _ = writeln!(res, "PathParent=..");
+    let _ = writeln!(res, "PathParentDouble=FALSE");
+    let _ = writeln!(res, "PathKeepPreDelimeter=FALSE");
+    let _ = writeln!(res);
+    let _ = writeln!(res, "ServerSoftware={}", env!("CARGO_PKG_NAME"));
+    let _ =
+        writeln!(res, "ServerSoftwareVersion={}", env!("CARGO_PKG_VERSION"));
+    let _ = writeln!(res, "ServerDefaultEncoding={}", caps.encoding);
+    for (key, value) in &[
+        ("ServerAdmin", &caps.admin),
+        ("ServerDescription", &caps.description),
+        ("ServerGeolocationString", &caps.geolocation),
+    ] {
+        if let Some(ref value) = **value {
+            let _ = writeln!(res, "{}={}", key, value);
+        }
+    }
+    res
+}
+
+/// Returns the generated robots.txt or caps.txt if `selector` requests one
+/// of them, they are configured and there is no such file in the data root
+/// directory.
+pub(crate) fn document(config: &Config, selector: &str) -> Option<String> {
+    let name = selector.trim_start_matches('/');
+    let res = match name {
+        "robots.txt" => config.robots.as_ref().map(robots),
+        "caps.txt" => config.caps.as_ref().map(caps),
+        _ => None,
+    }?;
+    if Path::new(&config.general.rootdir).join(name).is_file() {
+        return None;
+    }
+    Some(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use toml;
+
+    #[test]
+    fn test_robots() {
+        let config: Robots =
+            toml::from_str("disallow = [\"/internal\", \"/cgi-bin\"]\n")
+                .unwrap();
+        assert_eq!(
+            robots(&config),
+            "User-agent: *\nDisallow: /internal\nDisallow: /cgi-bin\n"
+        );
+        let config: Robots = toml::from_str("").unwrap();
+        assert_eq!(robots(&config), "User-agent: *\nDisallow:\n");
+    }
+
+    #[test]
+    fn test_caps() {
+        let config: Caps =
+            toml::from_str("admin = \"Jane <jane@example.org>\"\n").unwrap();
+        let caps = caps(&config);
+        assert!(caps.starts_with("CAPS\n\nCapsVersion=1\n"));
+        for line in &[
+            "ExpireCapsAfter=3600",
+            "PathDelimeter=/",
+            "ServerSoftware=rusty-gopher",
+            "ServerDefaultEncoding=UTF-8",
+            "ServerAdmin=Jane <jane@example.org>",
+        ] {
+            assert!(caps.lines().any(|l| l == *line), "missing {}", line);
+        }
+        assert!(!caps.contains("ServerDescription"));
+    }
+
+    #[test]
+    fn test_document() {
+        let mut config = Config::default();
+        config.general.rootdir = "/nonexistent".to_string();
+        assert_eq!(document(&config, "robots.txt"), None);
+        config.robots = Some(toml::from_str("").unwrap());
+        assert!(document(&config, "robots.txt").is_some());
+        assert!(document(&config, "/robots.txt").is_some());
+        assert_eq!(document(&config, "/docs/robots.txt"), None);
+    }
+}
@@ -0,0 +1,169 @@
+/*
+ *    Copyright (C) 2016-2018 Stefan Luecke
+ *                  2018 Nicolas Martin
+ *
+ *    This program is free software: you can redistribute it and/or modify
+ *    it under the terms of the GNU Affero General Public License as published
+ *    by the Free Software Foundation, either version 3 of the License, or
+ *    (at your option) any later version.
+ *
+ *    This program is distributed in the hope that it will be useful,
+ *    but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *    GNU Affero General Public License for more details.
+ *
+ *    You should have received a copy of the GNU Affero General Public License
+ *    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ *    Authors: Stefan Luecke <glaxx@glaxx.net>
+ *             Nicolas Martin <penguwingit@gmail.com>
+ */
+//! Running as a traditional daemon: detaching, PID file and log file.
+use super::std;
+use libc;
+use std::fs::{File, OpenOptions};
+use std::io::{Error, Write};
+use std::os::unix::io::AsRawFd;
+use std::sync::{Arc, Mutex};
+
+/// Detaches from the terminal by forking twice and starting a new session.
+/// Standard input and output are redirected to `/dev/null`, the working
+/// directory becomes `/`. Only the final child returns.
+///
+/// Has to be called before any threads are started.
+pub fn daemonize() -> Result<(), Error> {
+    fork()?;
+    if unsafe { libc::setsid() } < 0 {
+        return Err(Error::last_os_error());
+    }
+    // The session leader could acquire a terminal, its child can't
+    fork()?;
+    std::env::set_current_dir("/")?;
+
+    let null = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/null")?;
+    for fd in 0..3 {
+        if unsafe { libc::dup2(null.as_raw_fd(), fd) } < 0 {
+            return Err(Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Forks, letting the parent exit.
+fn fork() -> Result<(), Error> {
+    match unsafe { libc::fork() } {
+        -1 => Err(Error::last_os_error()),
+        0 => Ok(()),
+        _ => unsafe { libc::_exit(0) },
+    }
+}
+
+/// A file containing our process id, removed when dropped.
+pub struct PidFile {
+    path: String,
+}
+
+impl PidFile {
+    /// Writes our process id to `path`.
+    pub fn create(path: &str) -> Result<PidFile, Error> {
+        let mut file = File::create(path)?;
+        writeln!(file, "{}", std::process::id())?;
+        Ok(PidFile {
+            path: path.to_string(),
+        })
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// A log file which can be reopened after it has been rotated. Clones share
+/// the same file.
+#[derive(Clone)]
+pub struct LogFile {
+    path: String,
+    file: Arc<Mutex<File>>,
+}
+
+impl LogFile {
+    /// Opens `path` for appending, creating it if necessary.
+    pub fn open(path: &str) -> Result<LogFile, Error> {
+        Ok(LogFile {
+            path: path.to_string(),
+            file: Arc::new(Mutex::new(LogFile::append(path)?)),
+        })
+    }
+
+    fn append(path: &str) -> Result<File, Error> {
+        OpenOptions::new().append(true).create(true).open(path)
+    }
+
+    /// Closes the file and opens `path` again, so writes go to a new file
+    /// once the old one has been moved away.
+    pub fn reopen(&self) -> Result<(), Error> {
+        let file = LogFile::append(&self.path)?;
+        *self.file.lock().expect("log file lock poisoned") = file;
+        Ok(())
+    }
+
+    /// The path of the log file.
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+}
+
+impl Write for LogFile {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        self.file.lock().expect("log file lock poisoned").write(buf)
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        self.file.lock().expect("log file lock poisoned").flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn test_log_file_reopen() {
+        let path = std::env::temp_dir().join("rusty-gopher-logfile-test");
+        let rotated = path.with_extension("1");
+        let _ = std::fs::remove_file(&path);
+        let mut log = LogFile::open(path.to_str().unwrap()).unwrap();
+        log.write_all(b"before\n").unwrap();
+        std::fs::rename(&path, &rotated).unwrap();
+        log.write_all(b"still old\n").unwrap();
+        log.reopen().unwrap();
+        log.clone().write_all(b"after\n").unwrap();
+
+        let read = |p: &std::path::Path| {
+            let mut s = String::new();
+            File::open(p).unwrap().read_to_string(&mut s).unwrap();
+            std::fs::remove_file(p).unwrap();
+            s
+        };
+        assert_eq!(read(&rotated), "before\nstill old\n");
+        assert_eq!(read(&path), "after\n");
+    }
+
+    #[test]
+    fn test_pid_file() {
+        let path = std::env::temp_dir().join("rusty-gopher-pidfile-test");
+        let pidfile = PidFile::create(path.to_str().unwrap()).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            format!("{}\n", std::process::id())
+        );
+        drop(pidfile);
+        assert!(!path.exists());
+    }
+}
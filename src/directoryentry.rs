@@ -19,10 +19,10 @@
  *             Nicolas Martin <penguwingit@gmail.com>
  */
 use super::std;
+use gophermap::{self, Mode};
 use gophertype::GopherType;
-use std::str::FromStr;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct DirectoryEntry {
     pub gtype: GopherType,
     pub description: String,
@@ -71,6 +71,87 @@ impl DirectoryEntry {
         }
     }
 
+    /// Constructs an informational 'DirectoryEntry' displaying `description`
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let de = DirectoryEntry::info("Welcome!".to_string());
+    /// ```
+    pub fn info(description: String) -> DirectoryEntry {
+        DirectoryEntry {
+            gtype: GopherType::Informational,
+            description: description,
+            selector: "".to_string(),
+            host: "null.host".to_string(),
+            port: 1,
+        }
+    }
+
+    /// Constructs an error 'DirectoryEntry' displaying `description`
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let de = DirectoryEntry::error("Not found".to_string());
+    /// ```
+    pub fn error(description: String) -> DirectoryEntry {
+        DirectoryEntry {
+            gtype: GopherType::Error,
+            description: description,
+            selector: "".to_string(),
+            host: "error.host".to_string(),
+            port: 1,
+        }
+    }
+
+    /// Constructs a 'DirectoryEntry' linking to a web page with a `URL:`
+    /// selector. `host` and `port` should be our own, as clients not knowing
+    /// about `URL:` selectors request them from us to get a redirect page.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let de = DirectoryEntry::url(
+    ///     "Rust".to_string(),
+    ///     "https://www.rust-lang.org/",
+    ///     "localhost".to_string(),
+    ///     70,
+    /// );
+    /// ```
+    pub fn url(
+        description: String,
+        url: &str,
+        host: String,
+        port: u16,
+    ) -> DirectoryEntry {
+        DirectoryEntry {
+            gtype: GopherType::Html,
+            description: description,
+            selector: format!("URL:{}", url),
+            host: host,
+            port: port,
+        }
+    }
+
+    /// Returns the web address if this entry has a `URL:` selector.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let de = DirectoryEntry::from_string(
+    ///     "hRust\tURL:https://www.rust-lang.org/\tlocalhost\t70\r\n",
+    /// ).unwrap();
+    /// assert_eq!(de.url_target(), Some("https://www.rust-lang.org/"));
+    /// ```
+    pub fn url_target(&self) -> Option<&str> {
+        if self.selector.starts_with("URL:") {
+            Some(&self.selector[4..])
+        } else {
+            None
+        }
+    }
+
     pub fn from_dir_entry(
         e: std::fs::DirEntry,
         host: String,
@@ -106,55 +187,55 @@ impl DirectoryEntry {
         }
     }
 
+    /// Parses a single menu line.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let de = DirectoryEntry::from_string("1Phlog\t/phlog\tlocalhost\t70");
+    /// ```
     pub fn from_string(st: &str) -> Result<DirectoryEntry, String> {
-        let parsing_result = gopher_entry(st.as_bytes()).to_result();
-
-        match parsing_result {
-            Ok((g, d, s, h, p)) => {
-                return Ok(DirectoryEntry {
-                    gtype: GopherType::from_str(g),
-                    description: d.to_string(),
-                    selector: s.to_string(),
-                    host: h.to_string(),
-                    port: match p {
-                        Ok(o) => o,
-                        Err(e) => {
-                            println!("{}", e);
-                            0
-                        }
-                    },
-                })
-            }
-            Err(e) => Err(e.description().to_string()),
-        }
+        gophermap::parse_entry(
+            st.trim_end_matches(|c| c == '\r' || c == '\n'),
+            &Mode::Strict,
+        )
+        .map_err(|(column, kind)| format!("column {}: {}", column, kind))
     }
 }
 
-use nom::is_digit;
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-fn is_tab(chr: u8) -> bool {
-    chr == '\t' as u8
-}
+    #[test]
+    fn test_url() {
+        let de = DirectoryEntry::url(
+            "Rust".to_string(),
+            "https://www.rust-lang.org/",
+            "localhost".to_string(),
+            70,
+        );
+        assert_eq!(
+            de.to_string(),
+            "hRust\tURL:https://www.rust-lang.org/\tlocalhost\t70\r\n"
+        );
 
-named!(
-    gopher_entry<(
-        &str,
-        &str,
-        &str,
-        &str,
-        Result<u16, std::num::ParseIntError>
-    )>,
-    do_parse!(
-        gtype: map_res!(take!(1), std::str::from_utf8)
-            >> descr: map_res!(take_till!(is_tab), std::str::from_utf8)
-            >> tag_s!("\t")
-            >> selec: map_res!(take_till!(is_tab), std::str::from_utf8)
-            >> tag_s!("\t")
-            >> host: map_res!(take_till!(is_tab), std::str::from_utf8)
-            >> tag_s!("\t")
-            >> port: map!(
-                map_res!(dbg!(take_while!(is_digit)), std::str::from_utf8),
-                u16::from_str
-            ) >> (gtype, descr, selec, host, port)
-    )
-);
+        let parsed = DirectoryEntry::from_string(
+            "hRust\tURL:https://www.rust-lang.org/\tlocalhost\t70\r\n",
+        )
+        .unwrap();
+        assert_eq!(parsed.gtype, GopherType::Html);
+        assert_eq!(parsed.url_target(), Some("https://www.rust-lang.org/"));
+        assert_eq!(DirectoryEntry::new().url_target(), None);
+    }
+
+    #[test]
+    fn test_from_string_incomplete() {
+        assert!(DirectoryEntry::from_string("broken line").is_err());
+        assert_eq!(
+            DirectoryEntry::from_string("0About\t/about\tlocalhost\t-1\r\n")
+                .err(),
+            Some("column 25: invalid port \"-1\"".to_string())
+        );
+    }
+}
@@ -0,0 +1,223 @@
+/*
+ *    Copyright (C) 2016-2018 Stefan Luecke
+ *                  2018 Nicolas Martin
+ *
+ *    This program is free software: you can redistribute it and/or modify
+ *    it under the terms of the GNU Affero General Public License as published
+ *    by the Free Software Foundation, either version 3 of the License, or
+ *    (at your option) any later version.
+ *
+ *    This program is distributed in the hope that it will be useful,
+ *    but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *    GNU Affero General Public License for more details.
+ *
+ *    You should have received a copy of the GNU Affero General Public License
+ *    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ *    Authors: Stefan Luecke <glaxx@glaxx.net>
+ *             Nicolas Martin <penguwingit@gmail.com>
+ */
+//! Finger (RFC 1288) responder, serving `.plan` and `.project` files.
+use super::std;
+use slog;
+use std::io::{BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio;
+use tokio::net::TcpListener;
+use tokio::prelude::*;
+use users;
+use users::os::unix::UserExt;
+use {Config, ConfigHandle};
+
+/// A finger query.
+#[derive(Debug, PartialEq)]
+pub enum Query {
+    /// List all known users.
+    List,
+    /// Show a single user.
+    User(String),
+}
+
+/// Parses a finger query line. Queries for other hosts are rejected, as we
+/// don't forward requests.
+///
+/// # Examples
+///
+/// ```
+/// assert_eq!(parse_query("/W glaxx"), Ok(Query::User("glaxx".to_string())));
+/// ```
+pub fn parse_query(line: &str) -> Result<Query, &'static str> {
+    let mut query = line.trim();
+    // The verbose switch doesn't change what we send
+    if query.starts_with("/W") {
+        query = query[2..].trim_start();
+    }
+    if query.is_empty() {
+        return Ok(Query::List);
+    }
+    if query.contains('@') {
+        return Err("Finger forwarding service denied.");
+    }
+    let valid = !query.starts_with('.')
+        && query
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "._-".contains(c));
+    if !valid {
+        return Err("Invalid user name.");
+    }
+    Ok(Query::User(query.to_string()))
+}
+
+/// Reads a file, if it exists.
+fn read_file(path: &Path) -> Option<String> {
+    let mut content = String::new();
+    std::fs::File::open(path)
+        .and_then(|mut f| f.read_to_string(&mut content))
+        .ok()?;
+    Some(content)
+}
+
+/// Finds the directory containing `.plan` and `.project` of `user`. The
+/// configured directory takes precedence over system users.
+fn user_dir(config: &Config, user: &str) -> Option<PathBuf> {
+    let finger = config.finger.as_ref()?;
+    if let Some(ref dir) = finger.dir {
+        let path = PathBuf::from(format!(
+            "{}{}/{}",
+            config.general.rootdir, dir, user
+        ));
+        if path.is_dir() {
+            return Some(path);
+        }
+    }
+    if finger.systemusers {
+        return users::get_user_by_name(user)
+            .map(|u| u.home_dir().to_path_buf());
+    }
+    None
+}
+
+/// Lists the users of the configured directory.
+fn list_users(config: &Config) -> Vec<String> {
+    let dir = match config.finger.as_ref().and_then(|f| f.dir.as_ref()) {
+        Some(dir) => format!("{}{}", config.general.rootdir, dir),
+        None => return Vec::new(),
+    };
+    let mut users: Vec<String> = match std::fs::read_dir(dir) {
+        Ok(rd) => rd
+            .filter_map(|e| e.ok())
+            .filter(|e| e.file_type().map(|t| t.is_dir()).unwrap_or(false))
+            .filter_map(|e| e.file_name().into_string().ok())
+            .filter(|n| !n.starts_with('.'))
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+    users.sort();
+    users
+}
+
+/// Answers a single finger query line.
+fn handle(config: &Config, line: &str, log: &slog::Logger) -> String {
+    let query = match parse_query(line) {
+        Ok(q) => q,
+        Err(e) => return format!("{}\r\n", e),
+    };
+    info!(log, "got finger request"; "query" => ?query);
+
+    let text = match query {
+        Query::List => {
+            let users = list_users(config);
+            if users.is_empty() {
+                "No users to list.\n".to_string()
+            } else {
+                users.iter().map(|u| format!("{}\n", u)).collect()
+            }
+        }
+        Query::User(user) => match user_dir(config, &user) {
+            Some(dir) => {
+                let mut text = format!("Login: {}\n", user);
+                if let Some(p) = read_file(&dir.join(".project")) {
+                    text.push_str(&format!("Project: {}", p));
+                }
+                match read_file(&dir.join(".plan")) {
+                    Some(p) => text.push_str(&format!("Plan:\n{}", p)),
+                    None => text.push_str("No Plan.\n"),
+                }
+                text
+            }
+            None => format!("finger: {}: no such user.\n", user),
+        },
+    };
+    // RFC 1288 wants CRLF line endings
+    text.lines().map(|l| format!("{}\r\n", l)).collect()
+}
+
+/// Returns a future serving finger queries accepted on `listener`.
+///
+/// # Arguments
+///
+/// * `listener` - The bound finger listener.
+/// * `config` - The server configuration.
+/// * `log` - Logger for this listener.
+pub(crate) fn serve(
+    listener: TcpListener,
+    config: ConfigHandle,
+    log: slog::Logger,
+) -> impl Future<Item = (), Error = ()> {
+    let alog = log.clone();
+    listener
+        .incoming()
+        .map_err(move |e| error!(alog, "accept failed = {:?}", e))
+        .for_each(move |stream| {
+            let clog = log.new(o!("peer address" => format!(
+                "{}",
+                stream
+                    .peer_addr()
+                    .map(|a| a.to_string())
+                    .unwrap_or_default()
+            )));
+            let elog = clog.clone();
+            let config = config.current();
+            let timeout = Duration::from_secs(config.general.timeout);
+
+            let conn = tokio::io::read_until(
+                BufReader::new(stream),
+                b'\n',
+                Vec::new(),
+            )
+            .and_then(move |(stream, line)| {
+                let line = String::from_utf8_lossy(&line).into_owned();
+                let res = handle(&config, &line, &clog);
+                tokio::io::write_all(stream.into_inner(), res)
+            })
+            .timeout(timeout)
+            .map(|_| ())
+            .map_err(move |e| warn!(elog, "connection failed"; "error" => %e));
+            tokio::spawn(conn);
+            Ok(())
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_query() {
+        assert_eq!(parse_query("\r\n"), Ok(Query::List));
+        assert_eq!(parse_query("/W\r\n"), Ok(Query::List));
+        assert_eq!(
+            parse_query("glaxx\r\n"),
+            Ok(Query::User("glaxx".to_string()))
+        );
+        assert_eq!(
+            parse_query("/W penguwin"),
+            Ok(Query::User("penguwin".to_string()))
+        );
+        assert!(parse_query("glaxx@example.org").is_err());
+        assert!(parse_query("../etc").is_err());
+        assert!(parse_query("a/b").is_err());
+    }
+}
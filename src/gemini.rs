@@ -0,0 +1,338 @@
+/*
+ *    Copyright (C) 2016-2018 Stefan Luecke
+ *                  2018 Nicolas Martin
+ *
+ *    This program is free software: you can redistribute it and/or modify
+ *    it under the terms of the GNU Affero General Public License as published
+ *    by the Free Software Foundation, either version 3 of the License, or
+ *    (at your option) any later version.
+ *
+ *    This program is distributed in the hope that it will be useful,
+ *    but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *    GNU Affero General Public License for more details.
+ *
+ *    You should have received a copy of the GNU Affero General Public License
+ *    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ *    Authors: Stefan Luecke <glaxx@glaxx.net>
+ *             Nicolas Martin <penguwingit@gmail.com>
+ */
+//! Gemini listener, serving our content tree to gemini clients.
+use super::std;
+use acl;
+use content::{self, Content};
+use gophermap::Gophermap;
+use gophertype::GopherType;
+use gopherurl::{GopherUrl, SELECTOR_ENCODE_SET};
+use hostname::get_hostname;
+use mime_guess;
+use percent_encoding::{percent_decode, utf8_percent_encode};
+use slog;
+use std::io::{BufReader, ErrorKind};
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio;
+use tokio::net::TcpListener;
+use tokio::prelude::*;
+use tokio_tls::TlsAcceptor;
+use {Config, ConfigHandle};
+
+/// Maximum length of a request URL, as defined by the specification.
+const MAX_URL_LENGTH: usize = 1024;
+
+/// Turns a gemini request URL into a selector. Invalid requests yield the
+/// status code and message to answer with.
+///
+/// # Examples
+///
+/// ```
+/// let s = parse_request("gemini://example.org/phlog/");
+/// ```
+pub fn parse_request(url: &str) -> Result<String, (u8, &'static str)> {
+    if url.len() > MAX_URL_LENGTH {
+        return Err((59, "URL too long"));
+    }
+    let rest = match url.find("://") {
+        Some(i) if &url[..i] == "gemini" => &url[i + 3..],
+        Some(_) => return Err((53, "Proxy request refused")),
+        None => return Err((59, "Invalid URL")),
+    };
+    let path = match rest.find('/') {
+        Some(i) => &rest[i..],
+        None => "/",
+    };
+    let path = path.split(|c| c == '?' || c == '#').next().unwrap_or("/");
+    Ok(percent_decode(path.as_bytes())
+        .decode_utf8_lossy()
+        .into_owned())
+}
+
+/// Turns a menu into gemtext. Entries pointing to this server are linked with
+/// absolute paths, all others with `gopher://` URLs. Text lines that would
+/// read as gemtext markup are indented by a space.
+///
+/// # Arguments
+///
+/// * `map` - The menu.
+/// * `host` - Our host name.
+/// * `port` - Our gopher port.
+pub fn gemtext(map: &Gophermap, host: &str, port: u16) -> String {
+    let mut res = String::new();
+    for entry in &map.entries {
+        match entry.gtype {
+            GopherType::Informational | GopherType::Error => {
+                let markup = ["=>", "#", "*", ">", "```"];
+                if markup.iter().any(|m| entry.description.starts_with(m)) {
+                    res.push(' ');
+                }
+                res.push_str(&entry.description);
+            }
+            _ if entry.url_target().is_some() => {
+                if let Some(url) = entry.url_target() {
+                    res.push_str(&format!("=> {} {}", url, entry.description));
+                }
+            }
+            _ if entry.host == host && entry.port == port => {
+                let path = if entry.selector.starts_with('/') {
+                    entry.selector.clone()
+                } else {
+                    format!("/{}", entry.selector)
+                };
+                res.push_str(&format!(
+                    "=> {} {}",
+                    utf8_percent_encode(&path, SELECTOR_ENCODE_SET),
+                    entry.description
+                ));
+            }
+            _ => {
+                res.push_str(&format!(
+                    "=> {} {}",
+                    GopherUrl::from_entry(entry),
+                    entry.description
+                ));
+            }
+        }
+        res.push_str("\r\n");
+    }
+    res
+}
+
+/// Determines the MIME type of a document.
+fn mime_type(selector: &str, gtype: &GopherType) -> String {
+    let ext = Path::new(selector)
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or("")
+        .to_lowercase();
+    match (ext.as_str(), gtype) {
+        ("gmi", _) | ("gemini", _) => "text/gemini".to_string(),
+        (_, &GopherType::Gif) => "image/gif".to_string(),
+        (_, &GopherType::Html) => "text/html; charset=utf-8".to_string(),
+        (ext, gtype) => match mime_guess::get_mime_type_str(ext) {
+            Some(m) if m.starts_with("text/") => {
+                format!("{}; charset=utf-8", m)
+            }
+            Some(m) => m.to_string(),
+            None if *gtype == GopherType::File => {
+                "text/plain; charset=utf-8".to_string()
+            }
+            None => "application/octet-stream".to_string(),
+        },
+    }
+}
+
+/// Answers a single gemini request line of `peer`.
+fn handle(
+    config: &Config,
+    line: &str,
+    peer: &IpAddr,
+    port: u16,
+    log: &slog::Logger,
+) -> content::Response {
+    let selector = match parse_request(line) {
+        Ok(s) => s,
+        Err((status, e)) => {
+            return format!("{} {}\r\n", status, e).into_bytes().into()
+        }
+    };
+    info!(log, "got gemini request"; "selector" => &selector);
+
+    match acl::check(config, &selector, peer) {
+        Ok(acl::Action::Allow) => {}
+        Ok(acl::Action::Deny) => {
+            info!(log, "access denied"; "selector" => &selector);
+            return b"50 Access denied\r\n".to_vec().into();
+        }
+        Err(e) => {
+            warn!(log, "unable to check access"; "error" => %e);
+            return b"40 Temporary failure\r\n".to_vec().into();
+        }
+    }
+
+    let res = match content::resolve(config, &selector, port) {
+        Ok(Content::Menu(map)) => {
+            let hostname = get_hostname().expect("Failed to get hostname");
+            format!("20 text/gemini\r\n{}", gemtext(&map, &hostname, port))
+                .into_bytes()
+        }
+        Ok(Content::Document(gtype, data)) => {
+            let mut res =
+                format!("20 {}\r\n", mime_type(&selector, &gtype)).into_bytes();
+            res.extend(data);
+            res
+        }
+        Ok(Content::File(gtype, path)) => {
+            let header = format!("20 {}\r\n", mime_type(&selector, &gtype));
+            return content::Response {
+                data: header.into_bytes(),
+                file: Some(path),
+            };
+        }
+        Err(ref e) if e.kind() == ErrorKind::NotFound => {
+            b"51 Not found\r\n".to_vec()
+        }
+        Err(e) => {
+            warn!(log, "gemini request failed"; "selector" => &selector, "error" => %e);
+            b"40 Temporary failure\r\n".to_vec()
+        }
+    };
+    res.into()
+}
+
+/// Returns a future serving gemini requests accepted on `listener`.
+///
+/// # Arguments
+///
+/// * `listener` - The bound gemini listener.
+/// * `acceptor` - TLS acceptor holding our certificate.
+/// * `config` - The server configuration.
+/// * `port` - Our gopher port, local menu entries point to.
+/// * `log` - Logger for this listener.
+pub(crate) fn serve(
+    listener: TcpListener,
+    acceptor: TlsAcceptor,
+    config: ConfigHandle,
+    port: u16,
+    log: slog::Logger,
+) -> impl Future<Item = (), Error = ()> {
+    let alog = log.clone();
+    let acceptor = Arc::new(acceptor);
+    listener
+        .incoming()
+        .map_err(move |e| error!(alog, "accept failed = {:?}", e))
+        .for_each(move |stream| {
+            // The client is already gone if its address is unknown
+            let peer = match stream.peer_addr() {
+                Ok(peer) => peer,
+                Err(_) => return Ok(()),
+            };
+            let clog = log.new(o!("peer address" => format!("{}", peer)));
+            let elog = clog.clone();
+            let config = config.current();
+            let timeout = Duration::from_secs(config.general.timeout);
+
+            let conn = acceptor
+                .accept(stream)
+                .map_err(|e| std::io::Error::new(ErrorKind::Other, e))
+                .and_then(|stream| {
+                    tokio::io::read_until(
+                        BufReader::new(stream),
+                        b'\n',
+                        Vec::new(),
+                    )
+                })
+                .timeout(timeout)
+                .map_err(content::timed_out)
+                .and_then(move |(stream, line)| {
+                    let line = String::from_utf8_lossy(&line).into_owned();
+                    let res =
+                        handle(&config, line.trim(), &peer.ip(), port, &clog);
+                    res.send(stream.into_inner(), timeout)
+                })
+                .and_then(move |(stream, _)| {
+                    tokio::io::shutdown(stream)
+                        .timeout(timeout)
+                        .map_err(content::timed_out)
+                })
+                .map(|_| ())
+                .map_err(
+                    move |e| warn!(elog, "connection failed"; "error" => %e),
+                );
+            tokio::spawn(conn);
+            Ok(())
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use directoryentry::DirectoryEntry;
+
+    #[test]
+    fn test_parse_request() {
+        assert_eq!(parse_request("gemini://example.org"), Ok("/".to_string()));
+        assert_eq!(
+            parse_request("gemini://example.org:1965/a%20b.txt?q"),
+            Ok("/a b.txt".to_string())
+        );
+        assert_eq!(
+            parse_request("https://example.org/"),
+            Err((53, "Proxy request refused"))
+        );
+        assert_eq!(parse_request("/foo"), Err((59, "Invalid URL")));
+    }
+
+    #[test]
+    fn test_gemtext() {
+        let mut map = Gophermap::new();
+        map.entries
+            .push(DirectoryEntry::info("Welcome".to_string()));
+        for markup in &["# Title", "=> /x", "* item", "> quote", "```"] {
+            map.entries.push(DirectoryEntry::info(markup.to_string()));
+        }
+        map.entries.push(DirectoryEntry {
+            gtype: GopherType::Directory,
+            description: "Phlog".to_string(),
+            selector: "/phlog".to_string(),
+            host: "localhost".to_string(),
+            port: 70,
+        });
+        map.entries.push(DirectoryEntry {
+            gtype: GopherType::File,
+            description: "Elsewhere".to_string(),
+            selector: "/about.txt".to_string(),
+            host: "example.org".to_string(),
+            port: 7070,
+        });
+        map.entries.push(DirectoryEntry::url(
+            "Web".to_string(),
+            "https://example.org/",
+            "localhost".to_string(),
+            70,
+        ));
+        assert_eq!(
+            gemtext(&map, "localhost", 70),
+            "Welcome\r\n # Title\r\n => /x\r\n * item\r\n > quote\r\n ```\r\n\
+             => /phlog Phlog\r\n\
+             => gopher://example.org:7070/0/about.txt Elsewhere\r\n\
+             => https://example.org/ Web\r\n"
+        );
+    }
+
+    #[test]
+    fn test_mime_type() {
+        assert_eq!(mime_type("/index.gmi", &GopherType::File), "text/gemini");
+        assert_eq!(
+            mime_type("/README", &GopherType::File),
+            "text/plain; charset=utf-8"
+        );
+        assert_eq!(mime_type("/a.png", &GopherType::BinaryFile), "image/png");
+        assert_eq!(
+            mime_type("/a", &GopherType::BinaryFile),
+            "application/octet-stream"
+        );
+    }
+}
@@ -20,12 +20,260 @@
  */
 use super::std;
 use directoryentry::DirectoryEntry;
+use gophertype::GopherType;
+use std::io::{BufRead, BufReader, Read};
 
+/// Name of the files containing menus written by hand.
+pub const FILE_NAME: &'static str = "gophermap";
+
+/// Names of the fields following the item type, in order.
+const FIELDS: [&'static str; 4] = ["description", "selector", "host", "port"];
+
+/// How forgiving the parser is.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Mode {
+    /// Every line has to be a complete menu entry.
+    Strict,
+    /// Lines without tabs become informational lines, missing hosts and ports
+    /// are filled in with the given ones.
+    Lenient { host: String, port: u16 },
+}
+
+/// The reason a line could not be parsed.
+#[derive(Debug)]
+pub enum ParseErrorKind {
+    /// The line lacks the named field.
+    MissingField(&'static str),
+    /// The item type is unknown.
+    UnknownType(char),
+    /// The port is not a number between 0 and 65535.
+    InvalidPort(String),
+    /// The line is not valid UTF-8.
+    InvalidUtf8,
+    /// Reading the input failed.
+    Io(std::io::Error),
+}
+
+impl std::fmt::Display for ParseErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self {
+            ParseErrorKind::MissingField(field) => {
+                write!(f, "missing {}", field)
+            }
+            ParseErrorKind::UnknownType(t) => {
+                write!(f, "unknown item type '{}'", t)
+            }
+            ParseErrorKind::InvalidPort(ref p) => {
+                write!(f, "invalid port \"{}\"", p)
+            }
+            ParseErrorKind::InvalidUtf8 => write!(f, "invalid UTF-8"),
+            ParseErrorKind::Io(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
+/// An error at a position of a menu. Lines and columns count from 1, columns
+/// in characters.
 #[derive(Debug)]
+pub struct ParseError {
+    pub line: usize,
+    pub column: usize,
+    pub kind: ParseErrorKind,
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "line {}, column {}: {}",
+            self.line, self.column, self.kind
+        )
+    }
+}
+
+impl std::error::Error for ParseError {
+    fn description(&self) -> &str {
+        "invalid gophermap"
+    }
+}
+
+/// Parses a single menu line, without its line ending. Errors carry the
+/// column they occurred at.
+///
+/// # Examples
+///
+/// ```
+/// let de = parse_entry("1Phlog\t/phlog\tlocalhost\t70", &Mode::Strict);
+/// ```
+pub fn parse_entry(
+    line: &str,
+    mode: &Mode,
+) -> Result<DirectoryEntry, (usize, ParseErrorKind)> {
+    let defaults = match *mode {
+        Mode::Strict => None,
+        Mode::Lenient { ref host, port } => Some((host, port)),
+    };
+    if defaults.is_some() && !line.contains('\t') {
+        return Ok(DirectoryEntry::info(line.to_string()));
+    }
+
+    let t = match line.chars().next() {
+        Some(t) => t,
+        None => return Err((1, ParseErrorKind::MissingField("item type"))),
+    };
+    let gtype = GopherType::from_char(t);
+    if let (None, &GopherType::Other(t)) = (defaults, &gtype) {
+        return Err((1, ParseErrorKind::UnknownType(t)));
+    }
+
+    // Fields with the column they start at, Gopher+ fields are ignored
+    let mut fields = Vec::new();
+    let mut column = 2;
+    for field in line[t.len_utf8()..].split('\t').take(FIELDS.len()) {
+        fields.push((column, field));
+        column += field.chars().count() + 1;
+    }
+    if let Some((host, port)) = defaults {
+        if fields.len() < 3 || fields[2].1.is_empty() {
+            fields.truncate(2);
+            fields.push((column, host.as_str()));
+        }
+        if fields.len() < 4 || fields[3].1.is_empty() {
+            let entry = DirectoryEntry {
+                gtype: gtype,
+                description: fields[0].1.to_string(),
+                selector: fields[1].1.to_string(),
+                host: fields[2].1.to_string(),
+                port: port,
+            };
+            return Ok(entry);
+        }
+    }
+    if fields.len() < FIELDS.len() {
+        return Err((
+            column - 1,
+            ParseErrorKind::MissingField(FIELDS[fields.len()]),
+        ));
+    }
+
+    let (port_column, port) = fields[3];
+    let port = port.parse().map_err(|_| {
+        (port_column, ParseErrorKind::InvalidPort(port.to_string()))
+    })?;
+    Ok(DirectoryEntry {
+        gtype: gtype,
+        description: fields[0].1.to_string(),
+        selector: fields[1].1.to_string(),
+        host: fields[2].1.to_string(),
+        port: port,
+    })
+}
+
+/// Parses a menu line by line while reading it, yielding an entry or an error
+/// for every line. Parsing ends at the `.` terminator or the end of input.
+///
+/// # Examples
+///
+/// ```
+/// let file = std::fs::File::open("gophermap")?;
+/// for entry in Parser::new(file, Mode::Strict) {
+///     println!("{}", entry?);
+/// }
+/// ```
+pub struct Parser<R> {
+    input: BufReader<R>,
+    mode: Mode,
+    line: usize,
+    done: bool,
+}
+
+impl<R: Read> Parser<R> {
+    /// Constructs a parser reading from `input`.
+    pub fn new(input: R, mode: Mode) -> Parser<R> {
+        Parser {
+            input: BufReader::new(input),
+            mode: mode,
+            line: 0,
+            done: false,
+        }
+    }
+
+    /// Builds an error for the current line.
+    fn error(&self, column: usize, kind: ParseErrorKind) -> ParseError {
+        ParseError {
+            line: self.line,
+            column: column,
+            kind: kind,
+        }
+    }
+}
+
+impl<R: Read> Iterator for Parser<R> {
+    type Item = Result<DirectoryEntry, ParseError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let mut buf = Vec::new();
+        self.line += 1;
+        match self.input.read_until(b'\n', &mut buf) {
+            Ok(0) => {
+                self.done = true;
+                return None;
+            }
+            Ok(_) => {}
+            Err(e) => {
+                self.done = true;
+                return Some(Err(self.error(1, ParseErrorKind::Io(e))));
+            }
+        }
+
+        let line = match String::from_utf8(buf) {
+            Ok(line) => line,
+            Err(e) => match self.mode {
+                Mode::Strict => {
+                    let valid = e.utf8_error().valid_up_to();
+                    let column =
+                        String::from_utf8_lossy(&e.as_bytes()[..valid])
+                            .chars()
+                            .count()
+                            + 1;
+                    return Some(Err(
+                        self.error(column, ParseErrorKind::InvalidUtf8)
+                    ));
+                }
+                Mode::Lenient { .. } => {
+                    String::from_utf8_lossy(e.as_bytes()).into_owned()
+                }
+            },
+        };
+        let line = line.trim_end_matches(|c| c == '\r' || c == '\n');
+        if line == "." {
+            self.done = true;
+            return None;
+        }
+        Some(
+            parse_entry(line, &self.mode)
+                .map_err(|(column, kind)| self.error(column, kind)),
+        )
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct Gophermap {
     pub entries: Vec<DirectoryEntry>,
 }
 
+impl std::fmt::Display for Gophermap {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        for entry in &self.entries {
+            write!(f, "{}", entry)?;
+        }
+        write!(f, ".\r\n")
+    }
+}
+
 impl Gophermap {
     /// Constructs a new `Gophermap`.
     ///
@@ -40,22 +288,33 @@ impl Gophermap {
         }
     }
 
-    /// Generates a Gophermap from a string
+    /// Generates a Gophermap from a string, failing at the first line which
+    /// is not a complete menu entry.
     ///
     /// # Examples
     ///
     /// ```
     /// let m = Gophermap::from_string("");
     /// ```
-    pub fn from_string(input: &str) -> Result<Gophermap, &'static str> {
-        let mut result: Gophermap = Gophermap::new();
-        for line in input.lines() {
-            match DirectoryEntry::from_string(line) {
-                Ok(d) => result.entries.push(d),
-                Err(e) => println!("Error parsing line: {}", e), // TODO: Return error
-            }
-        }
-        Ok(result)
+    pub fn from_string(input: &str) -> Result<Gophermap, ParseError> {
+        Gophermap::from_reader(input.as_bytes(), Mode::Strict)
+    }
+
+    /// Reads a Gophermap from `input`, failing at the first error.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let file = std::fs::File::open("gophermap")?;
+    /// let m = Gophermap::from_reader(file, Mode::Strict);
+    /// ```
+    pub fn from_reader<R: Read>(
+        input: R,
+        mode: Mode,
+    ) -> Result<Gophermap, ParseError> {
+        Ok(Gophermap {
+            entries: Parser::new(input, mode).collect::<Result<_, _>>()?,
+        })
     }
 
     /// Generates a Gophermap out of directory entries.
@@ -122,6 +381,107 @@ mod tests {
         assert_eq!(parsed_map.entries[1].port, entry2.port);
     }
 
+    #[test]
+    fn test_parse_entry() {
+        let entry = parse_entry("1Phlog\t/phlog\tlocalhost\t70", &Mode::Strict)
+            .unwrap();
+        assert_eq!(entry.gtype, GopherType::Directory);
+        assert_eq!(entry.port, 70);
+        // Gopher+ fields are ignored
+        assert!(
+            parse_entry("1Phlog\t/phlog\tlocalhost\t70\t+", &Mode::Strict)
+                .is_ok()
+        );
+
+        match parse_entry("1Phlog\t/phlog\tlocalhost\tgopher", &Mode::Strict) {
+            Err((25, ParseErrorKind::InvalidPort(ref p))) if p == "gopher" => {}
+            e => panic!("unexpected result: {:?}", e),
+        }
+        match parse_entry("1Phlog\t/phlog", &Mode::Strict) {
+            Err((14, ParseErrorKind::MissingField("host"))) => {}
+            e => panic!("unexpected result: {:?}", e),
+        }
+        match parse_entry("xPhlog\t/phlog\tlocalhost\t70", &Mode::Strict) {
+            Err((1, ParseErrorKind::UnknownType('x'))) => {}
+            e => panic!("unexpected result: {:?}", e),
+        }
+
+        let entry =
+            parse_entry("7Search\t/search\tlocalhost\t70", &Mode::Strict)
+                .unwrap();
+        assert_eq!(entry.gtype, GopherType::Search);
+        let entry =
+            parse_entry("IPhoto\t/photo.jpg\tlocalhost\t70", &Mode::Strict)
+                .unwrap();
+        assert_eq!(entry.gtype, GopherType::Image);
+        assert_eq!(entry.to_string(), "IPhoto\t/photo.jpg\tlocalhost\t70\r\n");
+    }
+
+    #[test]
+    fn test_parse_lenient() {
+        let mode = Mode::Lenient {
+            host: "localhost".to_string(),
+            port: 7070,
+        };
+        let entry = parse_entry("Just some text", &mode).unwrap();
+        assert_eq!(entry.gtype, GopherType::Informational);
+        assert_eq!(entry.description, "Just some text");
+
+        let entry = parse_entry("0About\t/about.txt", &mode).unwrap();
+        assert_eq!(entry.host, "localhost");
+        assert_eq!(entry.port, 7070);
+
+        let entry =
+            parse_entry("1Floodgap\t/\tgopher.floodgap.com", &mode).unwrap();
+        assert_eq!(entry.host, "gopher.floodgap.com");
+        assert_eq!(entry.port, 7070);
+
+        assert!(parse_entry("1Phlog\t/phlog\tlocalhost\tx", &mode).is_err());
+
+        // Unknown types are kept
+        let line = "xThing\t/thing\tlocalhost\t70";
+        let entry = parse_entry(line, &mode).unwrap();
+        assert_eq!(entry.gtype, GopherType::Other('x'));
+        assert_eq!(entry.to_string(), format!("{}\r\n", line));
+    }
+
+    #[test]
+    fn test_parser() {
+        let input: &[u8] = b"iWelcome\t\tnull.host\t1\r\n\
+                             1Phlog\t/phlog\tlocalhost\t70\r\n\
+                             0Broken\t/broken\tlocalhost\r\n\
+                             0\xffBad\t/bad\tlocalhost\t70\r\n\
+                             .\r\n\
+                             0After\t/after\tlocalhost\t70\r\n";
+        let results: Vec<_> = Parser::new(input, Mode::Strict).collect();
+        assert_eq!(results.len(), 4);
+        assert!(results[0].is_ok() && results[1].is_ok());
+        match results[2] {
+            Err(ParseError {
+                line: 3,
+                column: 26,
+                kind: ParseErrorKind::MissingField("port"),
+            }) => {}
+            ref e => panic!("unexpected result: {:?}", e),
+        }
+        match results[3] {
+            Err(ParseError {
+                line: 4,
+                column: 2,
+                kind: ParseErrorKind::InvalidUtf8,
+            }) => {}
+            ref e => panic!("unexpected result: {:?}", e),
+        }
+
+        let err =
+            Gophermap::from_string("1Phlog\t/phlog\tlocalhost\t70000\r\n")
+                .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "line 1, column 25: invalid port \"70000\""
+        );
+    }
+
     #[bench]
     fn bench_from_str(b: &mut Bencher) {
         let teststr = format!("0About internet Gopher\tStuff:About us\trawBits.micro.umn.edu\t7070\r\n0About internet Gopher\tStuff:About us\trawBits.micro.umn.edu\t70\r\n");
@@ -21,7 +21,7 @@
 use self::GopherType::*;
 use super::std;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum GopherType {
     Informational,
     Gif,
@@ -29,49 +29,93 @@ pub enum GopherType {
     File,
     BinaryFile,
     Error,
+    Html,
+    CcsoNameServer,
+    BinHex,
+    DosFile,
+    UuEncoded,
+    Search,
+    Telnet,
+    Mirror,
+    Tn3270,
+    Sound,
+    Image,
+    /// A type we don't know, kept to be passed on unchanged.
+    Other(char),
 }
 
 impl std::fmt::Display for GopherType {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        match *self {
-            Informational => write!(f, "i"),
-            Gif => write!(f, "g"),
-            Directory => write!(f, "1"),
-            File => write!(f, "0"),
-            BinaryFile => write!(f, "9"),
-            Error => write!(f, "3"),
-        }
+        write!(f, "{}", self.to_char())
     }
 }
 
 impl GopherType {
     pub fn to_type_string(&self) -> String {
+        self.to_char().to_string()
+    }
+
+    /// Returns the character denoting the type in menus.
+    pub fn to_char(&self) -> char {
         match *self {
-            Informational => "i",
-            Gif => "g",
-            Directory => "1",
-            File => "0",
-            BinaryFile => "9",
-            Error => "3",
-        }.to_string()
+            Informational => 'i',
+            Gif => 'g',
+            Directory => '1',
+            File => '0',
+            BinaryFile => '9',
+            Error => '3',
+            Html => 'h',
+            CcsoNameServer => '2',
+            BinHex => '4',
+            DosFile => '5',
+            UuEncoded => '6',
+            Search => '7',
+            Telnet => '8',
+            Mirror => '+',
+            Tn3270 => 'T',
+            Sound => 's',
+            Image => 'I',
+            Other(c) => c,
+        }
     }
 
     pub fn from_str(s: &str) -> GopherType {
-        match s {
-            "i" => Informational,
-            "g" => Gif,
-            "1" => Directory,
-            "0" => File,
-            "9" => BinaryFile,
-            "3" => Error,
+        let mut chars = s.chars();
+        match (chars.next(), chars.next()) {
+            (Some(c), None) => GopherType::from_char(c),
             _ => Error,
         }
     }
 
+    /// Returns the type denoted by `c`, `Other` if it is unknown.
+    pub fn from_char(c: char) -> GopherType {
+        match c {
+            'i' => Informational,
+            'g' => Gif,
+            '1' => Directory,
+            '0' => File,
+            '9' => BinaryFile,
+            '3' => Error,
+            'h' => Html,
+            '2' => CcsoNameServer,
+            '4' => BinHex,
+            '5' => DosFile,
+            '6' => UuEncoded,
+            '7' => Search,
+            '8' => Telnet,
+            '+' => Mirror,
+            'T' => Tn3270,
+            's' => Sound,
+            'I' => Image,
+            c => Other(c),
+        }
+    }
+
     pub fn from_file_extension(s: &str) -> GopherType {
         match s {
             "txt" | "md" => File,
             "gif" => Gif,
+            "html" | "htm" => Html,
             _ => BinaryFile,
         }
     }
@@ -89,6 +133,7 @@ mod tests {
         assert_eq!(GopherType::to_type_string(&GopherType::File), "0");
         assert_eq!(GopherType::to_type_string(&GopherType::BinaryFile), "9");
         assert_eq!(GopherType::to_type_string(&GopherType::Error), "3");
+        assert_eq!(GopherType::to_type_string(&GopherType::Html), "h");
     }
 
     #[test]
@@ -99,7 +144,14 @@ mod tests {
         assert_eq!(GopherType::from_str("0"), GopherType::File);
         assert_eq!(GopherType::from_str("9"), GopherType::BinaryFile);
         assert_eq!(GopherType::from_str("3"), GopherType::Error);
-        assert_eq!(GopherType::from_str("7"), GopherType::Error);
+        assert_eq!(GopherType::from_str("h"), GopherType::Html);
+        assert_eq!(GopherType::from_str("7"), GopherType::Search);
+        assert_eq!(GopherType::from_str("I"), GopherType::Image);
+        assert_eq!(GopherType::from_str("x"), GopherType::Other('x'));
+        assert_eq!(GopherType::from_str("xy"), GopherType::Error);
+        for t in "ig1093h245678+TsIx".chars() {
+            assert_eq!(GopherType::from_char(t).to_char(), t);
+        }
     }
 
     #[test]
@@ -107,6 +159,7 @@ mod tests {
         assert_eq!(GopherType::from_file_extension("txt"), GopherType::File);
         assert_eq!(GopherType::from_file_extension("md"), GopherType::File);
         assert_eq!(GopherType::from_file_extension("gif"), GopherType::Gif);
+        assert_eq!(GopherType::from_file_extension("html"), GopherType::Html);
         assert_eq!(
             GopherType::from_file_extension("wtf"),
             GopherType::BinaryFile
@@ -0,0 +1,249 @@
+/*
+ *    Copyright (C) 2016-2018 Stefan Luecke
+ *                  2018 Nicolas Martin
+ *
+ *    This program is free software: you can redistribute it and/or modify
+ *    it under the terms of the GNU Affero General Public License as published
+ *    by the Free Software Foundation, either version 3 of the License, or
+ *    (at your option) any later version.
+ *
+ *    This program is distributed in the hope that it will be useful,
+ *    but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *    GNU Affero General Public License for more details.
+ *
+ *    You should have received a copy of the GNU Affero General Public License
+ *    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ *    Authors: Stefan Luecke <glaxx@glaxx.net>
+ *             Nicolas Martin <penguwingit@gmail.com>
+ */
+//! Gopher URLs as described in RFC 4266.
+use super::std;
+use directoryentry::DirectoryEntry;
+use gophertype::GopherType;
+use percent_encoding::{percent_decode, utf8_percent_encode};
+
+/// Port used when an URL doesn't name one.
+pub const DEFAULT_PORT: u16 = 70;
+
+define_encode_set! {
+    /// Characters to escape when turning a selector into an URL path.
+    pub SELECTOR_ENCODE_SET = [::percent_encoding::DEFAULT_ENCODE_SET] | {'%'}
+}
+
+/// A parsed `gopher://` URL.
+#[derive(Debug, PartialEq)]
+pub struct GopherUrl {
+    pub host: String,
+    pub port: u16,
+    pub gtype: GopherType,
+    /// The percent-decoded selector.
+    pub selector: String,
+    /// The search string sent to search items, if any.
+    pub search: Option<String>,
+    /// The Gopher+ string, if any.
+    pub gopherplus: Option<String>,
+}
+
+impl GopherUrl {
+    /// Parses a `gopher://` URL. A missing port defaults to 70, a missing
+    /// type to a directory.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let url = GopherUrl::parse("gopher://example.org/0/about.txt").unwrap();
+    /// assert_eq!(url.selector, "/about.txt");
+    /// ```
+    pub fn parse(url: &str) -> Result<GopherUrl, String> {
+        let scheme = "gopher://";
+        if !url
+            .get(..scheme.len())
+            .map_or(false, |s| s.eq_ignore_ascii_case(scheme))
+        {
+            return Err(format!("not a gopher URL: {}", url));
+        }
+        let rest = &url[scheme.len()..];
+        let (hostport, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i + 1..]),
+            None => (rest, ""),
+        };
+
+        // IPv6 addresses are enclosed in brackets
+        let (host, port) = if hostport.starts_with('[') {
+            match hostport.find(']') {
+                Some(i) => (&hostport[1..i], &hostport[i + 1..]),
+                None => return Err(format!("invalid host: {}", hostport)),
+            }
+        } else {
+            match hostport.rfind(':') {
+                Some(i) => (&hostport[..i], &hostport[i..]),
+                None => (hostport, ""),
+            }
+        };
+        if host.is_empty() {
+            return Err(format!("missing host: {}", url));
+        }
+        let port = match port {
+            "" | ":" => DEFAULT_PORT,
+            p if p.starts_with(':') => p[1..]
+                .parse()
+                .map_err(|_| format!("invalid port: {}", &p[1..]))?,
+            p => return Err(format!("invalid host: {}{}", host, p)),
+        };
+
+        if path.is_empty() {
+            return Ok(GopherUrl {
+                host: host.to_string(),
+                port: port,
+                gtype: GopherType::Directory,
+                selector: String::new(),
+                search: None,
+                gopherplus: None,
+            });
+        }
+        let t = path.chars().next().unwrap_or('1');
+        let gtype = GopherType::from_char(t);
+        let path = percent_decode(path[t.len_utf8()..].as_bytes())
+            .decode_utf8()
+            .map_err(|e| format!("invalid selector: {}", e))?;
+        let mut parts = path.splitn(3, '\t');
+        Ok(GopherUrl {
+            host: host.to_string(),
+            port: port,
+            gtype: gtype,
+            selector: parts.next().unwrap_or("").to_string(),
+            search: parts.next().map(|s| s.to_string()),
+            gopherplus: parts.next().map(|s| s.to_string()),
+        })
+    }
+
+    /// Constructs the URL pointing to the item of a menu entry.
+    pub fn from_entry(entry: &DirectoryEntry) -> GopherUrl {
+        GopherUrl {
+            host: entry.host.clone(),
+            port: entry.port,
+            gtype: entry.gtype.clone(),
+            selector: entry.selector.clone(),
+            search: None,
+            gopherplus: None,
+        }
+    }
+
+    /// Constructs a menu entry pointing to this URL. Search and Gopher+
+    /// strings can't be expressed in a menu and are dropped.
+    pub fn to_entry(&self, description: String) -> DirectoryEntry {
+        DirectoryEntry {
+            gtype: self.gtype.clone(),
+            description: description,
+            selector: self.selector.clone(),
+            host: self.host.clone(),
+            port: self.port,
+        }
+    }
+}
+
+impl std::fmt::Display for GopherUrl {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if self.host.contains(':') {
+            write!(f, "gopher://[{}]:{}/", self.host, self.port)?;
+        } else {
+            write!(f, "gopher://{}:{}/", self.host, self.port)?;
+        }
+        write!(
+            f,
+            "{}{}",
+            self.gtype,
+            utf8_percent_encode(&self.selector, SELECTOR_ENCODE_SET)
+        )?;
+        // The Gopher+ string follows the search string, even an empty one
+        if self.search.is_some() || self.gopherplus.is_some() {
+            write!(
+                f,
+                "%09{}",
+                utf8_percent_encode(
+                    self.search.as_ref().map(|s| s.as_str()).unwrap_or(""),
+                    SELECTOR_ENCODE_SET
+                )
+            )?;
+        }
+        if let Some(ref gopherplus) = self.gopherplus {
+            write!(
+                f,
+                "%09{}",
+                utf8_percent_encode(gopherplus, SELECTOR_ENCODE_SET)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let url = GopherUrl::parse("gopher://example.org").unwrap();
+        assert_eq!(url.host, "example.org");
+        assert_eq!(url.port, 70);
+        assert_eq!(url.gtype, GopherType::Directory);
+        assert_eq!(url.selector, "");
+
+        let url =
+            GopherUrl::parse("GOPHER://example.org:7070/0/about%20us.txt")
+                .unwrap();
+        assert_eq!(url.port, 7070);
+        assert_eq!(url.gtype, GopherType::File);
+        assert_eq!(url.selector, "/about us.txt");
+        assert_eq!(url.search, None);
+
+        let url =
+            GopherUrl::parse("gopher://[::1]:70/1/search%09rust%09+").unwrap();
+        assert_eq!(url.host, "::1");
+        assert_eq!(url.selector, "/search");
+        assert_eq!(url.search, Some("rust".to_string()));
+        assert_eq!(url.gopherplus, Some("+".to_string()));
+
+        let url =
+            GopherUrl::parse("gopher://example.org/7/v2/vs%09rust").unwrap();
+        assert_eq!(url.gtype, GopherType::Search);
+        assert_eq!(url.selector, "/v2/vs");
+        assert_eq!(url.search, Some("rust".to_string()));
+
+        assert!(GopherUrl::parse("https://example.org/").is_err());
+        assert!(GopherUrl::parse("gopher://example.org:http/").is_err());
+        assert!(GopherUrl::parse("gopher:///1/").is_err());
+        assert!(GopherUrl::parse("ééééé.md").is_err());
+        assert!(GopherUrl::parse("é").is_err());
+    }
+
+    #[test]
+    fn test_display() {
+        for url in &[
+            "gopher://example.org:70/1",
+            "gopher://example.org:7070/0/about%20us.txt",
+            "gopher://[::1]:70/1/search%09rust",
+            "gopher://example.org:70/1/%09%09+",
+            "gopher://example.org:70/7/v2/vs%09rust",
+            "gopher://example.org:70/7/search%09gopher%20server",
+            "gopher://example.org:70/I/photo.jpg",
+            "gopher://example.org:70/x/unknown",
+        ] {
+            assert_eq!(&GopherUrl::parse(url).unwrap().to_string(), url);
+        }
+    }
+
+    #[test]
+    fn test_entry() {
+        let url =
+            GopherUrl::parse("gopher://gopher.floodgap.com/1/world").unwrap();
+        let entry = url.to_entry("Floodgap".to_string());
+        assert_eq!(
+            entry.to_string(),
+            "1Floodgap\t/world\tgopher.floodgap.com\t70\r\n"
+        );
+        assert_eq!(GopherUrl::from_entry(&entry), url);
+    }
+}
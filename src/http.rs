@@ -0,0 +1,393 @@
+/*
+ *    Copyright (C) 2016-2018 Stefan Luecke
+ *                  2018 Nicolas Martin
+ *
+ *    This program is free software: you can redistribute it and/or modify
+ *    it under the terms of the GNU Affero General Public License as published
+ *    by the Free Software Foundation, either version 3 of the License, or
+ *    (at your option) any later version.
+ *
+ *    This program is distributed in the hope that it will be useful,
+ *    but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *    GNU Affero General Public License for more details.
+ *
+ *    You should have received a copy of the GNU Affero General Public License
+ *    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ *    Authors: Stefan Luecke <glaxx@glaxx.net>
+ *             Nicolas Martin <penguwingit@gmail.com>
+ */
+//! Gopher-to-HTTP gateway, rendering our content as HTML for web browsers.
+use acl;
+use content::{self, Content};
+use gophermap::Gophermap;
+use gophertype::GopherType;
+use gopherurl::{GopherUrl, SELECTOR_ENCODE_SET};
+use hostname::get_hostname;
+use percent_encoding::{percent_decode, utf8_percent_encode};
+use slog;
+use std::io::{BufReader, Error, ErrorKind};
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio;
+use tokio::net::TcpListener;
+use tokio::prelude::*;
+use {Config, ConfigHandle};
+
+/// Escapes the characters HTML treats specially.
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Wraps `body` into a complete HTML document.
+fn page(title: &str, body: &str) -> String {
+    format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+         <title>{}</title>\n<style>\n\
+         ul {{ list-style: none; padding: 0; font-family: monospace; \
+         white-space: pre; }}\n</style>\n</head>\n<body>\n{}</body>\n</html>\n",
+        escape(title),
+        body
+    )
+}
+
+/// Turns a menu into a list of links. Entries pointing to this server are
+/// linked with URL paths, all others with `gopher://` URLs.
+///
+/// # Arguments
+///
+/// * `map` - The menu.
+/// * `title` - The page title.
+/// * `host` - Our host name.
+/// * `port` - Our gopher port.
+pub fn menu(map: &Gophermap, title: &str, host: &str, port: u16) -> String {
+    let mut body = String::from("<ul>\n");
+    for entry in &map.entries {
+        let description = escape(&entry.description);
+        let href = match entry.gtype {
+            GopherType::Informational | GopherType::Error => None,
+            _ if entry.url_target().is_some() => {
+                entry.url_target().map(|u| u.to_string())
+            }
+            _ if entry.host == host && entry.port == port => {
+                let path = if entry.selector.starts_with('/') {
+                    entry.selector.clone()
+                } else {
+                    format!("/{}", entry.selector)
+                };
+                Some(
+                    utf8_percent_encode(&path, SELECTOR_ENCODE_SET).to_string(),
+                )
+            }
+            _ => Some(GopherUrl::from_entry(entry).to_string()),
+        };
+        match href {
+            Some(href) => body.push_str(&format!(
+                "<li><a href=\"{}\">{}</a></li>\n",
+                escape(&href),
+                description
+            )),
+            None => body.push_str(&format!("<li>{}</li>\n", description)),
+        }
+    }
+    body.push_str("</ul>\n");
+    page(title, &body)
+}
+
+/// Renders a text document as preformatted text.
+pub fn text(content: &[u8], title: &str) -> String {
+    page(
+        title,
+        &format!("<pre>{}</pre>\n", escape(&String::from_utf8_lossy(content))),
+    )
+}
+
+/// Builds the status line and headers of a response with a body of
+/// `length` bytes.
+fn header(status: &str, headers: &[(&str, String)], length: u64) -> Vec<u8> {
+    let mut res = format!("HTTP/1.0 {}\r\n", status);
+    for &(name, ref value) in headers {
+        res.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    res.push_str(&format!("Content-Length: {}\r\n", length));
+    res.push_str("Connection: close\r\n\r\n");
+    res.into_bytes()
+}
+
+/// Builds a complete HTTP response.
+pub(crate) fn response(
+    status: &str,
+    headers: &[(&str, String)],
+    body: Vec<u8>,
+    head: bool,
+) -> Vec<u8> {
+    let mut res = header(status, headers, body.len() as u64);
+    if !head {
+        res.extend(body);
+    }
+    res
+}
+
+/// Builds a successful response whose body is the file at `path`, sent from
+/// disk.
+fn file_response(
+    headers: &[(&str, String)],
+    path: PathBuf,
+    head: bool,
+) -> Result<content::Response, Error> {
+    let length = path.metadata()?.len();
+    Ok(content::Response {
+        data: header("200 OK", headers, length),
+        file: if head { None } else { Some(path) },
+    })
+}
+
+/// Returns the headers for a document of type `gtype` that is served as is.
+fn document_headers(
+    gtype: &GopherType,
+    selector: &str,
+) -> Vec<(&'static str, String)> {
+    match *gtype {
+        GopherType::File => {
+            vec![("Content-Type", "text/plain; charset=utf-8".to_string())]
+        }
+        GopherType::Html => {
+            vec![("Content-Type", "text/html; charset=utf-8".to_string())]
+        }
+        GopherType::Gif => vec![("Content-Type", "image/gif".to_string())],
+        _ => {
+            let name: String = selector
+                .rsplit('/')
+                .next()
+                .unwrap_or("download")
+                .chars()
+                .filter(|c| !c.is_control() && *c != '"')
+                .collect();
+            vec![
+                ("Content-Type", "application/octet-stream".to_string()),
+                (
+                    "Content-Disposition",
+                    format!("attachment; filename=\"{}\"", name),
+                ),
+            ]
+        }
+    }
+}
+
+/// Builds the response for unexpected failures.
+fn server_error(head: bool) -> Vec<u8> {
+    response(
+        "500 Internal Server Error",
+        &[("Content-Type", "text/html; charset=utf-8".to_string())],
+        page("Internal Server Error", "<h1>Internal Server Error</h1>\n")
+            .into_bytes(),
+        head,
+    )
+}
+
+/// Answers a single HTTP request line of `peer`.
+fn handle(
+    config: &Config,
+    line: &str,
+    peer: &IpAddr,
+    port: u16,
+    log: &slog::Logger,
+) -> content::Response {
+    let html = || ("Content-Type", "text/html; charset=utf-8".to_string());
+    let parts: Vec<&str> = line.split_whitespace().collect();
+    if parts.len() < 2 {
+        return response(
+            "400 Bad Request",
+            &[html()],
+            page("Bad Request", "<h1>Bad Request</h1>\n").into_bytes(),
+            false,
+        )
+        .into();
+    }
+    let head = parts[0] == "HEAD";
+    if parts[0] != "GET" && !head {
+        return response(
+            "405 Method Not Allowed",
+            &[html(), ("Allow", "GET, HEAD".to_string())],
+            page("Method Not Allowed", "<h1>Method Not Allowed</h1>\n")
+                .into_bytes(),
+            false,
+        )
+        .into();
+    }
+
+    let path = parts[1].split('?').next().unwrap_or("/");
+    let selector = percent_decode(path.as_bytes())
+        .decode_utf8_lossy()
+        .into_owned();
+    info!(log, "got http request"; "selector" => &selector);
+
+    match acl::check(config, &selector, peer) {
+        Ok(acl::Action::Allow) => {}
+        Ok(acl::Action::Deny) => {
+            info!(log, "access denied"; "selector" => &selector);
+            return response(
+                "403 Forbidden",
+                &[html()],
+                page("Forbidden", "<h1>Forbidden</h1>\n").into_bytes(),
+                head,
+            )
+            .into();
+        }
+        Err(e) => {
+            warn!(log, "unable to check access"; "error" => %e);
+            return server_error(head).into();
+        }
+    }
+
+    let hostname = get_hostname().expect("Failed to get hostname");
+    let res = match content::resolve(config, &selector, port) {
+        Ok(Content::Menu(map)) => response(
+            "200 OK",
+            &[html()],
+            menu(&map, &selector, &hostname, port).into_bytes(),
+            head,
+        ),
+        Ok(Content::Document(GopherType::File, data)) => response(
+            "200 OK",
+            &[html()],
+            text(&data, &selector).into_bytes(),
+            head,
+        ),
+        Ok(Content::Document(gtype, data)) => {
+            response("200 OK", &document_headers(&gtype, &selector), data, head)
+        }
+        Ok(Content::File(gtype, path)) => {
+            let headers = document_headers(&gtype, &selector);
+            match file_response(&headers, path, head) {
+                Ok(res) => return res,
+                Err(e) => {
+                    warn!(log, "http request failed"; "selector" => &selector, "error" => %e);
+                    server_error(head)
+                }
+            }
+        }
+        Err(ref e) if e.kind() == ErrorKind::NotFound => response(
+            "404 Not Found",
+            &[html()],
+            page("Not Found", "<h1>Not Found</h1>\n").into_bytes(),
+            head,
+        ),
+        Err(e) => {
+            warn!(log, "http request failed"; "selector" => &selector, "error" => %e);
+            server_error(head)
+        }
+    };
+    res.into()
+}
+
+/// Returns a future serving HTTP requests accepted on `listener`.
+///
+/// # Arguments
+///
+/// * `listener` - The bound HTTP listener.
+/// * `config` - The server configuration.
+/// * `port` - Our gopher port, local menu entries point to.
+/// * `log` - Logger for this listener.
+pub(crate) fn serve(
+    listener: TcpListener,
+    config: ConfigHandle,
+    port: u16,
+    log: slog::Logger,
+) -> impl Future<Item = (), Error = ()> {
+    let alog = log.clone();
+    listener
+        .incoming()
+        .map_err(move |e| error!(alog, "accept failed = {:?}", e))
+        .for_each(move |stream| {
+            // The client is already gone if its address is unknown
+            let peer = match stream.peer_addr() {
+                Ok(peer) => peer,
+                Err(_) => return Ok(()),
+            };
+            let clog = log.new(o!("peer address" => format!("{}", peer)));
+            let elog = clog.clone();
+            let config = config.current();
+            let timeout = Duration::from_secs(config.general.timeout);
+
+            let conn = tokio::io::read_until(
+                BufReader::new(stream),
+                b'\n',
+                Vec::new(),
+            )
+            .timeout(timeout)
+            .map_err(content::timed_out)
+            .and_then(move |(stream, line)| {
+                let line = String::from_utf8_lossy(&line).into_owned();
+                let res = handle(&config, line.trim(), &peer.ip(), port, &clog);
+                res.send(stream.into_inner(), timeout)
+            })
+            .map(|_| ())
+            .map_err(move |e| warn!(elog, "connection failed"; "error" => %e));
+            tokio::spawn(conn);
+            Ok(())
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use directoryentry::DirectoryEntry;
+
+    #[test]
+    fn test_menu() {
+        let mut map = Gophermap::new();
+        map.entries
+            .push(DirectoryEntry::info("<Welcome>".to_string()));
+        map.entries.push(DirectoryEntry {
+            gtype: GopherType::File,
+            description: "About".to_string(),
+            selector: "/about us.txt".to_string(),
+            host: "localhost".to_string(),
+            port: 70,
+        });
+        map.entries.push(DirectoryEntry {
+            gtype: GopherType::Directory,
+            description: "Floodgap".to_string(),
+            selector: "/world".to_string(),
+            host: "gopher.floodgap.com".to_string(),
+            port: 70,
+        });
+        map.entries.push(DirectoryEntry::url(
+            "Web".to_string(),
+            "https://example.org/",
+            "localhost".to_string(),
+            70,
+        ));
+        let html = menu(&map, "/", "localhost", 70);
+        assert!(
+            html.contains("<li><a href=\"https://example.org/\">Web</a></li>")
+        );
+        assert!(html.contains("<li>&lt;Welcome&gt;</li>"));
+        assert!(html.contains("<li><a href=\"/about%20us.txt\">About</a></li>"));
+        assert!(html.contains(
+            "<li><a href=\"gopher://gopher.floodgap.com:70/1/world\">\
+             Floodgap</a></li>"
+        ));
+    }
+
+    #[test]
+    fn test_response() {
+        let res = response(
+            "200 OK",
+            &[("Content-Type", "text/plain".to_string())],
+            b"hello".to_vec(),
+            false,
+        );
+        assert_eq!(
+            String::from_utf8(res).unwrap(),
+            "HTTP/1.0 200 OK\r\nContent-Type: text/plain\r\n\
+             Content-Length: 5\r\nConnection: close\r\n\r\nhello"
+        );
+    }
+}
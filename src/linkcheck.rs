@@ -0,0 +1,343 @@
+/*
+ *    Copyright (C) 2016-2018 Stefan Luecke
+ *                  2018 Nicolas Martin
+ *
+ *    This program is free software: you can redistribute it and/or modify
+ *    it under the terms of the GNU Affero General Public License as published
+ *    by the Free Software Foundation, either version 3 of the License, or
+ *    (at your option) any later version.
+ *
+ *    This program is distributed in the hope that it will be useful,
+ *    but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *    GNU Affero General Public License for more details.
+ *
+ *    You should have received a copy of the GNU Affero General Public License
+ *    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ *    Authors: Stefan Luecke <glaxx@glaxx.net>
+ *             Nicolas Martin <penguwingit@gmail.com>
+ */
+//! Checker finding dead menu entries in gophermaps and generated menus.
+use super::std;
+use client;
+use content::{self, Content};
+use crawlers;
+use directoryentry::DirectoryEntry;
+use gophermap::{Mode, Parser};
+use gophertype::GopherType;
+use gopherurl::GopherUrl;
+use lint;
+use phlog;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::Error;
+use std::path::Path;
+use std::time::Duration;
+use tokio;
+use tokio::prelude::*;
+use Config;
+
+/// Number of remote servers probed at the same time.
+const CONCURRENT_PROBES: usize = 8;
+
+/// What is wrong with a menu entry.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Status {
+    /// The item doesn't exist or has the wrong type.
+    Broken(String),
+    /// The server of the item couldn't be reached.
+    Unreachable(String),
+    /// The item redirects to the given web page.
+    Redirected(String),
+}
+
+/// A menu entry which needs attention.
+#[derive(Debug)]
+pub struct Report {
+    /// The gophermap or selector of the menu containing the entry.
+    pub source: String,
+    pub description: String,
+    /// The URL the entry points to.
+    pub target: String,
+    pub status: Status,
+}
+
+impl std::fmt::Display for Report {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let (status, reason) = match self.status {
+            Status::Broken(ref r) => ("broken", r),
+            Status::Unreachable(ref r) => ("unreachable", r),
+            Status::Redirected(ref r) => ("redirected", r),
+        };
+        write!(
+            f,
+            "{}: \"{}\" ({}) is {}: {}",
+            self.source, self.description, self.target, status, reason
+        )
+    }
+}
+
+/// Collects the entries of all gophermaps and all menus reachable from the
+/// root menu and the phlogs, together with the menu they appear in.
+fn collect(
+    config: &Config,
+    hosts: &[String],
+    port: u16,
+) -> Result<Vec<(String, DirectoryEntry)>, Error> {
+    let mut res = Vec::new();
+
+    let mut paths = Vec::new();
+    lint::find_gophermaps(Path::new(&config.general.rootdir), &mut paths)?;
+    for path in paths {
+        let file = std::fs::File::open(&path)?;
+        // Syntax errors are the linter's business
+        for entry in Parser::new(file, Mode::Strict).filter_map(|e| e.ok()) {
+            res.push((path.display().to_string(), entry));
+        }
+    }
+
+    let mut queue: VecDeque<String> = VecDeque::new();
+    queue.push_back("/".to_string());
+    queue.extend(config.phlog.iter().map(|p| p.selector.clone()));
+    let mut visited = HashSet::new();
+    while let Some(selector) = queue.pop_front() {
+        if !visited.insert(selector.clone()) {
+            continue;
+        }
+        let map = match content::resolve(config, &selector, port) {
+            Ok(Content::Menu(map)) => map,
+            _ => continue,
+        };
+        for entry in map.entries {
+            if entry.gtype == GopherType::Directory
+                && hosts.contains(&entry.host)
+                && entry.port == port
+            {
+                queue.push_back(entry.selector.clone());
+            }
+            res.push((selector.clone(), entry));
+        }
+    }
+    Ok(res)
+}
+
+/// Tells whether the local `selector` is served as a menu, without loading
+/// it.
+fn is_menu(config: &Config, selector: &str) -> Result<bool, Error> {
+    if crawlers::document(config, selector).is_some() {
+        return Ok(false);
+    }
+    if let Some((_, request)) = config.phlog_request(selector) {
+        return Ok(request != phlog::Request::Feed);
+    }
+    let (path, selector) = content::local_path(config, selector)?;
+    Ok(path.metadata()?.is_dir()
+        || config.markdown.rendering(&selector) == Some(GopherType::Directory))
+}
+
+/// Checks an entry pointing to this server.
+fn check_local(config: &Config, entry: &DirectoryEntry) -> Option<Status> {
+    if let Some(url) = entry.url_target() {
+        return Some(Status::Redirected(url.to_string()));
+    }
+    match (is_menu(config, &entry.selector), &entry.gtype) {
+        (Err(e), _) => Some(Status::Broken(e.to_string())),
+        (Ok(true), &GopherType::Directory) => None,
+        (Ok(true), _) => {
+            Some(Status::Broken("is a menu, not a document".to_string()))
+        }
+        (Ok(false), &GopherType::Directory) => {
+            Some(Status::Broken("is a document, not a menu".to_string()))
+        }
+        (Ok(false), _) => None,
+    }
+}
+
+/// Checks the response of a remote server.
+fn check_response(url: &GopherUrl, data: &[u8]) -> Option<Status> {
+    if data.is_empty() {
+        return Some(Status::Broken("empty response".to_string()));
+    }
+    if url.gtype == GopherType::Directory {
+        let map = client::parse_menu(data, url.host.clone(), url.port);
+        if let Some(e) = map.entries.first() {
+            if e.gtype == GopherType::Error {
+                return Some(Status::Broken(e.description.clone()));
+            }
+        }
+    }
+    None
+}
+
+/// Requests all `urls` from their servers, returning the problems found.
+/// Host names are resolved up front, one after the other.
+fn probe(
+    urls: Vec<GopherUrl>,
+    timeout: Duration,
+) -> Result<HashMap<String, Status>, Error> {
+    let mut res = HashMap::new();
+    let mut resolved = Vec::new();
+    for url in urls {
+        match client::resolve(&url) {
+            Ok(addr) => resolved.push((url, addr)),
+            Err(e) => {
+                res.insert(url.to_string(), Status::Unreachable(e.to_string()));
+            }
+        }
+    }
+    let probes = stream::iter_ok(resolved)
+        .map(move |(url, addr)| {
+            client::fetch(&url, &addr, timeout).then(move |res| {
+                let status = match res {
+                    Ok(data) => check_response(&url, &data),
+                    Err(e) => Some(Status::Unreachable(e.to_string())),
+                };
+                Ok::<_, Error>((url.to_string(), status))
+            })
+        })
+        .buffer_unordered(CONCURRENT_PROBES)
+        .filter_map(|(url, status)| status.map(|s| (url, s)))
+        .collect();
+    let probed = tokio::runtime::Runtime::new()?.block_on(probes)?;
+    res.extend(probed);
+    Ok(res)
+}
+
+/// Checks all menu entries served by us. Entries pointing to other servers
+/// are only checked if `remote` gives the time to wait for them.
+///
+/// # Arguments
+///
+/// * `config` - The server configuration.
+/// * `remote` - Optional: Timeout for probing other servers.
+pub(crate) fn check(
+    config: &Config,
+    remote: Option<Duration>,
+) -> Result<Vec<Report>, Error> {
+    let (hosts, port) = lint::local_address(config);
+    let items: Vec<(String, DirectoryEntry)> = collect(config, &hosts, port)?
+        .into_iter()
+        .filter(|&(_, ref e)| match e.gtype {
+            GopherType::Informational | GopherType::Error => false,
+            _ => true,
+        })
+        .collect();
+
+    let mut remote_status = HashMap::new();
+    if let Some(timeout) = remote {
+        let mut urls: Vec<GopherUrl> = items
+            .iter()
+            .filter(|&&(_, ref e)| {
+                !(hosts.contains(&e.host) && e.port == port)
+                    && e.url_target().is_none()
+            })
+            .map(|&(_, ref e)| GopherUrl::from_entry(e))
+            .collect();
+        urls.sort_by_key(|u| u.to_string());
+        urls.dedup_by_key(|u| u.to_string());
+        remote_status = probe(urls, timeout)?;
+    }
+
+    let mut res = Vec::new();
+    for (source, entry) in items {
+        let target = match entry.url_target() {
+            Some(url) => url.to_string(),
+            None => GopherUrl::from_entry(&entry).to_string(),
+        };
+        let status = if entry.url_target().is_some()
+            || (hosts.contains(&entry.host) && entry.port == port)
+        {
+            check_local(config, &entry)
+        } else {
+            remote_status.get(&target).cloned()
+        };
+        if let Some(status) = status {
+            res.push(Report {
+                source: source,
+                description: entry.description,
+                target: target,
+                status: status,
+            });
+        }
+    }
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::net::TcpListener;
+
+    #[test]
+    fn test_check() {
+        let dir = std::env::temp_dir().join("rusty-gopher-linkcheck-test");
+        std::fs::create_dir_all(dir.join("docs")).unwrap();
+        std::fs::File::create(dir.join("docs/about.txt")).unwrap();
+
+        // A port nobody listens on
+        let closed = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        std::fs::File::create(dir.join("gophermap"))
+            .unwrap()
+            .write_all(
+                format!(
+                    "0About\t/docs/about.txt\tlocalhost\t70\r\n\
+                     0Docs\t/docs\tlocalhost\t70\r\n\
+                     1Gone\t/gone\tlocalhost\t70\r\n\
+                     hRust\tURL:https://www.rust-lang.org/\tlocalhost\t70\r\n\
+                     1Closed\t/\t127.0.0.1\t{}\r\n",
+                    closed
+                )
+                .as_bytes(),
+            )
+            .unwrap();
+
+        let mut config = Config::default();
+        config.general.rootdir = dir.to_str().unwrap().to_string();
+        config.general.listento = "127.0.0.1:70".to_string();
+        let local = check(&config, None).unwrap();
+        // Menus are loaded to find their entries, documents are not
+        assert_eq!(config.contents.stats().entries, 2);
+        let remote = check(&config, Some(Duration::from_secs(5))).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let statuses: Vec<&Status> = local.iter().map(|r| &r.status).collect();
+        assert_eq!(statuses.len(), 3);
+        assert_eq!(
+            statuses[0],
+            &Status::Broken("is a menu, not a document".to_string())
+        );
+        match statuses[1] {
+            &Status::Broken(_) => {}
+            s => panic!("unexpected status: {:?}", s),
+        }
+        assert_eq!(local[1].description, "Gone");
+        assert_eq!(
+            statuses[2],
+            &Status::Redirected("https://www.rust-lang.org/".to_string())
+        );
+
+        assert_eq!(remote.len(), 4);
+        assert_eq!(remote[3].description, "Closed");
+        match remote[3].status {
+            Status::Unreachable(_) => {}
+            ref s => panic!("unexpected status: {:?}", s),
+        }
+    }
+
+    #[test]
+    fn test_check_response() {
+        let menu = GopherUrl::parse("gopher://example.org/1/").unwrap();
+        let file = GopherUrl::parse("gopher://example.org/0/a.txt").unwrap();
+        assert_eq!(
+            check_response(&menu, b"3Not found\t\terror.host\t1\r\n.\r\n"),
+            Some(Status::Broken("Not found".to_string()))
+        );
+        assert_eq!(check_response(&file, b"Hello\r\n"), None);
+        assert!(check_response(&file, b"").is_some());
+    }
+}
@@ -0,0 +1,316 @@
+/*
+ *    Copyright (C) 2016-2018 Stefan Luecke
+ *                  2018 Nicolas Martin
+ *
+ *    This program is free software: you can redistribute it and/or modify
+ *    it under the terms of the GNU Affero General Public License as published
+ *    by the Free Software Foundation, either version 3 of the License, or
+ *    (at your option) any later version.
+ *
+ *    This program is distributed in the hope that it will be useful,
+ *    but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *    GNU Affero General Public License for more details.
+ *
+ *    You should have received a copy of the GNU Affero General Public License
+ *    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ *    Authors: Stefan Luecke <glaxx@glaxx.net>
+ *             Nicolas Martin <penguwingit@gmail.com>
+ */
+//! Linter for the gophermaps within the data root directory.
+use super::std;
+use content;
+use directoryentry::DirectoryEntry;
+use gophermap::{self, Mode, ParseError, ParseErrorKind, Parser};
+use hostname::get_hostname;
+use std::io::Error;
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+use Config;
+
+/// Number of columns a description may take up.
+const MAX_WIDTH: usize = 70;
+
+/// How bad a finding is.
+#[derive(Debug, PartialEq)]
+pub enum Severity {
+    /// The menu works, but probably not as intended.
+    Warning,
+    /// The line is broken.
+    Error,
+}
+
+/// A problem found in a gophermap.
+#[derive(Debug)]
+pub struct Finding {
+    pub path: PathBuf,
+    pub line: usize,
+    pub column: usize,
+    pub severity: Severity,
+    pub message: String,
+}
+
+impl std::fmt::Display for Finding {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{}:{}:{}: {}: {}",
+            self.path.display(),
+            self.line,
+            self.column,
+            match self.severity {
+                Severity::Warning => "warning",
+                Severity::Error => "error",
+            },
+            self.message
+        )
+    }
+}
+
+/// Collects all gophermaps below `dir`. Symbolic links to directories are not
+/// followed, they could form loops.
+pub(crate) fn find_gophermaps(dir: &Path, res: &mut Vec<PathBuf>) -> Result<(), Error> {
+    let mut entries = std::fs::read_dir(dir)?
+        .map(|e| e.map(|e| e.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+    entries.sort();
+    for path in entries {
+        if path.symlink_metadata()?.is_dir() {
+            find_gophermaps(&path, res)?;
+        } else if path.file_name().and_then(|n| n.to_str())
+            == Some(gophermap::FILE_NAME)
+        {
+            res.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Checks whether a local selector resolves to anything.
+fn selector_exists(config: &Config, selector: &str) -> bool {
+    if selector.is_empty() || selector.starts_with("URL:") {
+        return true;
+    }
+    if Path::new(selector)
+        .components()
+        .any(|c| c == Component::ParentDir)
+    {
+        return false;
+    }
+    config.phlog_request(selector).is_some()
+        || content::local_path(config, selector).is_ok()
+}
+
+/// Checks a parsed entry for problems the parser accepts.
+///
+/// # Arguments
+///
+/// * `config` - The server configuration.
+/// * `entry` - The entry to check.
+/// * `hosts` - Host names referring to this server.
+/// * `port` - Our gopher port.
+fn check_entry(
+    config: &Config,
+    entry: &DirectoryEntry,
+    hosts: &[String],
+    port: u16,
+) -> Vec<(usize, Severity, String)> {
+    let mut res = Vec::new();
+    let width = entry.description.chars().count();
+    if width > MAX_WIDTH {
+        res.push((
+            2 + MAX_WIDTH,
+            Severity::Warning,
+            format!(
+                "description is {} columns wide, more than {}",
+                width, MAX_WIDTH
+            ),
+        ));
+    }
+
+    let selector_column = width + 3;
+    if let Some(i) = entry.selector.chars().position(|c| !c.is_ascii()) {
+        res.push((
+            selector_column + i,
+            Severity::Warning,
+            "selector contains non-ASCII characters".to_string(),
+        ));
+    }
+    if hosts.contains(&entry.host)
+        && entry.port == port
+        && !selector_exists(config, &entry.selector)
+    {
+        res.push((
+            selector_column,
+            Severity::Error,
+            format!("local selector \"{}\" does not exist", entry.selector),
+        ));
+    }
+    res
+}
+
+/// Describes a parser error, hinting at the usual cause of missing fields.
+fn parse_finding(path: &Path, e: ParseError, line: &str) -> Finding {
+    let (severity, message) = match e.kind {
+        ParseErrorKind::UnknownType(t) => {
+            (Severity::Warning, format!("unknown item type '{}'", t))
+        }
+        ParseErrorKind::MissingField(field)
+            if !line.contains('\t') && line.contains(' ') =>
+        {
+            (
+                Severity::Error,
+                format!(
+                    "missing {} (fields are separated by tabs, not spaces)",
+                    field
+                ),
+            )
+        }
+        kind => (Severity::Error, kind.to_string()),
+    };
+    Finding {
+        path: path.to_path_buf(),
+        line: e.line,
+        column: e.column,
+        severity: severity,
+        message: message,
+    }
+}
+
+/// Lints a single gophermap.
+///
+/// # Arguments
+///
+/// * `config` - The server configuration.
+/// * `path` - The gophermap to lint.
+/// * `hosts` - Host names referring to this server.
+/// * `port` - Our gopher port.
+pub(crate) fn lint_file(
+    config: &Config,
+    path: &Path,
+    hosts: &[String],
+    port: u16,
+) -> Result<Vec<Finding>, Error> {
+    let content = std::fs::read(path)?;
+    let lines: Vec<String> = String::from_utf8_lossy(&content)
+        .lines()
+        .map(|l| l.to_string())
+        .collect();
+    let mut res = Vec::new();
+    for (i, result) in Parser::new(&content[..], Mode::Strict).enumerate() {
+        let line = lines.get(i).map(|l| l.as_str()).unwrap_or("");
+        match result {
+            Ok(entry) => {
+                for (column, severity, message) in
+                    check_entry(config, &entry, hosts, port)
+                {
+                    res.push(Finding {
+                        path: path.to_path_buf(),
+                        line: i + 1,
+                        column: column,
+                        severity: severity,
+                        message: message,
+                    });
+                }
+            }
+            Err(e) => res.push(parse_finding(path, e, line)),
+        }
+    }
+    Ok(res)
+}
+
+/// Returns the host names and port menu entries pointing to this server use.
+pub(crate) fn local_address(config: &Config) -> (Vec<String>, u16) {
+    let hosts = vec![
+        get_hostname().expect("Failed to get hostname"),
+        "localhost".to_string(),
+    ];
+    let port = std::net::SocketAddr::from_str(&config.general.listento)
+        .map(|a| a.port())
+        .unwrap_or(70);
+    (hosts, port)
+}
+
+/// Lints every gophermap within the data root directory.
+pub(crate) fn lint(config: &Config) -> Result<Vec<Finding>, Error> {
+    let mut paths = Vec::new();
+    find_gophermaps(Path::new(&config.general.rootdir), &mut paths)?;
+
+    let (hosts, port) = local_address(config);
+    let mut res = Vec::new();
+    for path in paths {
+        res.extend(lint_file(config, &path, &hosts, port)?);
+    }
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn test_lint_file() {
+        let dir = std::env::temp_dir().join("rusty-gopher-lint-test");
+        std::fs::create_dir_all(dir.join("docs")).unwrap();
+        let path = dir.join(gophermap::FILE_NAME);
+        std::fs::File::create(&path)
+            .unwrap()
+            .write_all(
+                "1Docs\t/docs\tlocalhost\t70\r\n\
+                 1Gone\t/gone\tlocalhost\t70\r\n\
+                 1Elsewhere\t/gone\texample.org\t70\r\n\
+                 0About /about localhost 70\r\n\
+                 xOdd\t/docs\tlocalhost\t70\r\n\
+                 0Caf\u{e9}\t/caf\u{e9}\texample.org\t70\r\n\
+                 7Search\t/search\tlocalhost\t70\r\n\
+                 IPhotos\t/docs\tlocalhost\t70\r\n"
+                    .as_bytes(),
+            )
+            .unwrap();
+
+        let mut config = Config::default();
+        config.general.rootdir = dir.to_str().unwrap().to_string();
+        let hosts = vec!["localhost".to_string()];
+        let findings: Vec<String> = lint_file(&config, &path, &hosts, 70)
+            .unwrap()
+            .iter()
+            .map(|f| f.to_string()[path.to_str().unwrap().len()..].to_string())
+            .collect();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(
+            findings,
+            vec![
+                ":2:7: error: local selector \"/gone\" does not exist",
+                ":4:27: error: missing selector \
+                 (fields are separated by tabs, not spaces)",
+                ":5:1: warning: unknown item type 'x'",
+                ":6:11: warning: selector contains non-ASCII characters",
+                ":7:9: error: local selector \"/search\" does not exist",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_find_gophermaps() {
+        let dir = std::env::temp_dir().join("rusty-gopher-find-test");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("a")).unwrap();
+        std::fs::write(dir.join(gophermap::FILE_NAME), "").unwrap();
+        std::fs::write(dir.join("a").join(gophermap::FILE_NAME), "").unwrap();
+        std::os::unix::fs::symlink(&dir, dir.join("a").join("loop")).unwrap();
+
+        let mut paths = Vec::new();
+        find_gophermaps(&dir, &mut paths).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(
+            paths,
+            vec![
+                dir.join("a").join(gophermap::FILE_NAME),
+                dir.join(gophermap::FILE_NAME),
+            ]
+        );
+    }
+}
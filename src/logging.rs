@@ -0,0 +1,264 @@
+/*
+ *    Copyright (C) 2016-2018 Stefan Luecke
+ *                  2018 Nicolas Martin
+ *
+ *    This program is free software: you can redistribute it and/or modify
+ *    it under the terms of the GNU Affero General Public License as published
+ *    by the Free Software Foundation, either version 3 of the License, or
+ *    (at your option) any later version.
+ *
+ *    This program is distributed in the hope that it will be useful,
+ *    but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *    GNU Affero General Public License for more details.
+ *
+ *    You should have received a copy of the GNU Affero General Public License
+ *    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ *    Authors: Stefan Luecke <glaxx@glaxx.net>
+ *             Nicolas Martin <penguwingit@gmail.com>
+ */
+//! Construction of the root logger from the logging section of the config.
+use super::std;
+use daemon::LogFile;
+use slog::{self, Drain};
+use slog_json;
+use slog_syslog;
+use slog_term;
+use std::io::{self, Error, ErrorKind, Write};
+use std::sync::Mutex;
+use Logging;
+
+/// The least severe messages that are logged.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Level {
+    Critical,
+    Error,
+    Warning,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    fn to_slog(self) -> slog::Level {
+        match self {
+            Level::Critical => slog::Level::Critical,
+            Level::Error => slog::Level::Error,
+            Level::Warning => slog::Level::Warning,
+            Level::Info => slog::Level::Info,
+            Level::Debug => slog::Level::Debug,
+            Level::Trace => slog::Level::Trace,
+        }
+    }
+}
+
+impl Default for Level {
+    fn default() -> Self {
+        Level::Info
+    }
+}
+
+/// Where messages are written to.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Output {
+    Stdout,
+    Stderr,
+    /// The file given by `path`, reopened on SIGUSR1.
+    File,
+    /// The local syslog daemon.
+    Syslog,
+}
+
+impl Default for Output {
+    fn default() -> Self {
+        Output::Stdout
+    }
+}
+
+/// How messages are formatted. Syslog uses its own format.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    /// A line with all key-value pairs per message.
+    Human,
+    /// Key-value pairs shared with the previous messages are left out.
+    Compact,
+    /// A JSON object per line.
+    Json,
+}
+
+impl Default for Format {
+    fn default() -> Self {
+        Format::Human
+    }
+}
+
+/// The stream messages are written to.
+enum Writer {
+    Stdout(io::Stdout),
+    Stderr(io::Stderr),
+    File(LogFile),
+}
+
+impl Write for Writer {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        match *self {
+            Writer::Stdout(ref mut w) => w.write(buf),
+            Writer::Stderr(ref mut w) => w.write(buf),
+            Writer::File(ref mut w) => w.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        match *self {
+            Writer::Stdout(ref mut w) => w.flush(),
+            Writer::Stderr(ref mut w) => w.flush(),
+            Writer::File(ref mut w) => w.flush(),
+        }
+    }
+}
+
+/// Drops messages less severe than the level of the module they come from.
+struct ModuleFilter<D> {
+    drain: D,
+    level: slog::Level,
+    /// Full module paths and their levels, most specific first.
+    modules: Vec<(String, slog::Level)>,
+}
+
+impl<D> ModuleFilter<D> {
+    fn new(drain: D, logging: &Logging) -> ModuleFilter<D> {
+        let krate = module_path!().split("::").next().unwrap_or("");
+        let mut modules: Vec<(String, slog::Level)> = logging
+            .modules
+            .iter()
+            .map(|(module, level)| {
+                let path = if module == krate
+                    || module.starts_with(&format!("{}::", krate))
+                {
+                    module.clone()
+                } else {
+                    format!("{}::{}", krate, module)
+                };
+                (path, level.to_slog())
+            })
+            .collect();
+        modules.sort_by_key(|&(ref path, _)| std::cmp::Reverse(path.len()));
+        ModuleFilter {
+            drain: drain,
+            level: logging.level.to_slog(),
+            modules: modules,
+        }
+    }
+
+    /// Returns the level in effect for `module`.
+    fn level_for(&self, module: &str) -> slog::Level {
+        self.modules
+            .iter()
+            .find(|&&(ref path, _)| {
+                module == path || module.starts_with(&format!("{}::", path))
+            })
+            .map_or(self.level, |&(_, level)| level)
+    }
+}
+
+impl<D: Drain> Drain for ModuleFilter<D> {
+    type Ok = ();
+    type Err = D::Err;
+
+    fn log(
+        &self,
+        record: &slog::Record,
+        values: &slog::OwnedKVList,
+    ) -> Result<(), D::Err> {
+        if record.level().is_at_least(self.level_for(record.module())) {
+            self.drain.log(record, values)?;
+        }
+        Ok(())
+    }
+}
+
+/// Creates the root logger described by `logging`. Returns the log file as
+/// well if there is one, so it can be reopened.
+pub(crate) fn root(
+    logging: &Logging,
+) -> Result<(slog::Logger, Option<LogFile>), Error> {
+    let values = o!(env!("CARGO_PKG_NAME") => env!("CARGO_PKG_VERSION"));
+    let logfile = match logging.output {
+        Output::Stdout | Output::Stderr => None,
+        Output::File => {
+            let path = logging.path.as_ref().ok_or_else(|| {
+                Error::new(ErrorKind::InvalidInput, "no log file configured")
+            })?;
+            Some(LogFile::open(path)?)
+        }
+        Output::Syslog => {
+            let drain =
+                slog_syslog::unix_3164(slog_syslog::Facility::LOG_DAEMON)?;
+            let drain = ModuleFilter::new(drain.ignore_res(), logging);
+            return Ok((slog::Logger::root(drain, values), None));
+        }
+    };
+    let writer = || match logfile {
+        Some(ref logfile) => Writer::File(logfile.clone()),
+        None if logging.output == Output::Stderr => {
+            Writer::Stderr(io::stderr())
+        }
+        None => Writer::Stdout(io::stdout()),
+    };
+
+    let logger = match logging.format {
+        Format::Human => {
+            let decorator = slog_term::PlainSyncDecorator::new(writer());
+            let drain = slog_term::FullFormat::new(decorator).build().fuse();
+            slog::Logger::root(ModuleFilter::new(drain, logging), values)
+        }
+        Format::Compact => {
+            let decorator = slog_term::PlainSyncDecorator::new(writer());
+            let drain = slog_term::CompactFormat::new(decorator).build();
+            let drain = Mutex::new(drain).fuse();
+            slog::Logger::root(ModuleFilter::new(drain, logging), values)
+        }
+        Format::Json => {
+            let drain =
+                slog_json::Json::new(writer()).add_default_keys().build();
+            let drain = Mutex::new(drain).fuse();
+            slog::Logger::root(ModuleFilter::new(drain, logging), values)
+        }
+    };
+    Ok((logger, logfile))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use toml;
+
+    #[test]
+    fn test_level_for() {
+        let logging: Logging = toml::from_str(
+            "level = \"warning\"\n\
+             [modules]\n\
+             http = \"debug\"\n\
+             \"rusty_gopher::gemini\" = \"error\"\n\
+             rusty_gopher = \"info\"\n",
+        )
+        .unwrap();
+        let filter = ModuleFilter::new((), &logging);
+        assert_eq!(filter.level_for("rusty_gopher::http"), slog::Level::Debug);
+        assert_eq!(
+            filter.level_for("rusty_gopher::http::gateway"),
+            slog::Level::Debug
+        );
+        assert_eq!(filter.level_for("rusty_gopher::httpd"), slog::Level::Info);
+        assert_eq!(
+            filter.level_for("rusty_gopher::gemini"),
+            slog::Level::Error
+        );
+        assert_eq!(filter.level_for("rusty_gopher"), slog::Level::Info);
+        assert_eq!(filter.level_for("slog_term"), slog::Level::Warning);
+    }
+}
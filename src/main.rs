@@ -29,42 +29,81 @@ extern crate serde;
 #[macro_use]
 extern crate serde_derive;
 extern crate libc;
+extern crate mime_guess;
+extern crate native_tls;
 extern crate toml;
 #[macro_use]
 extern crate slog;
+extern crate slog_json;
+extern crate slog_syslog;
 extern crate slog_term;
-#[macro_use]
-extern crate nom;
 extern crate hostname;
+#[macro_use]
+extern crate percent_encoding;
+extern crate pulldown_cmark;
 extern crate tokio;
+extern crate tokio_signal;
+extern crate tokio_tls;
 extern crate users;
 
+pub mod accesslog;
+pub mod acl;
+pub mod cache;
+pub mod cidr;
+pub mod client;
+pub mod content;
+pub mod crawlers;
+pub mod daemon;
 pub mod directoryentry;
+pub mod finger;
+pub mod gemini;
 pub mod gophermap;
 pub mod gophertype;
+pub mod gopherurl;
+pub mod http;
+pub mod linkcheck;
+pub mod lint;
+pub mod logging;
+pub mod markdown;
+pub mod metrics;
+pub mod phlog;
+pub mod ratelimit;
+pub mod status;
+pub mod systemd;
 
 use directoryentry::*;
 use docopt::Docopt;
+use gophermap::Gophermap;
 use gophertype::*;
 use hostname::get_hostname;
-use slog::Drain;
 use std::{
-    default::Default, fs::File, io::{Read, Write},
+    default::Default, fs::File, io::{BufReader, Read, Write},
     process::{exit, ExitCode, Termination}, str::FromStr,
+    collections::BTreeMap, sync::{Arc, Mutex, RwLock},
+    time::{Duration, Instant},
 };
-use tokio::{net::TcpListener, prelude::*};
+use tokio::{net::TcpListener, prelude::*, prelude::future::Shared};
 use users::{get_current_uid, get_user_by_name};
 
 const USAGE: &'static str = "
 Usage:
-    rusty-gopher  serve [<config>]
+    rusty-gopher  serve [--daemon] [<config>]
     rusty-gopher  genconfig [<config>]
+    rusty-gopher  check-config [<config>]
+    rusty-gopher  lint [<config>]
+    rusty-gopher  check-links [--remote] [--timeout=<secs>] [<config>]
+    rusty-gopher  fetch [--raw] [--output=<file>] [--timeout=<secs>] <url>
     rusty-gopher  -h | --help
     rusty-gopher  --version
 
 Options:
-    -h --help   Show this screen.
-    --version   Show version.
+    -h --help           Show this screen.
+    --version           Show version.
+    -d --daemon         Detach from the terminal and run in the background.
+    --raw               Print the response as received instead of formatting.
+    -o --output=<file>  Save the response to <file>.
+    --remote            Probe menu entries pointing to other servers as well.
+    --timeout=<secs>    Seconds to wait for the server [default: 30].
 ";
 
 /// The default config file path
@@ -79,8 +118,32 @@ struct Args {
     /// Is true when the genconfig command was used. Indicates that we should
     /// generate a fresh config.
     cmd_genconfig: bool,
+    /// Is true when the check-config command was used. Indicates that we
+    /// should only validate the config.
+    cmd_check_config: bool,
+    /// Is true when the lint command was used. Indicates that we should check
+    /// the gophermaps within our root directory.
+    cmd_lint: bool,
+    /// Is true when the check-links command was used. Indicates that we
+    /// should look for dead menu entries.
+    cmd_check_links: bool,
+    /// Is true when the fetch command was used. Indicates that we should
+    /// retrieve `arg_url` from another server.
+    cmd_fetch: bool,
+    /// Detach from the terminal when serving.
+    flag_daemon: bool,
     /// Optional: Path to user supplied config file.
     arg_config: Option<String>,
+    /// The gopher URL to fetch.
+    arg_url: Option<String>,
+    /// Print fetched responses without formatting them.
+    flag_raw: bool,
+    /// Optional: File to save fetched responses to.
+    flag_output: Option<String>,
+    /// Probe remote menu entries when checking links.
+    flag_remote: bool,
+    /// Seconds to wait for the server when fetching or checking links.
+    flag_timeout: u64,
 }
 
 /// Writes a config file with default values to the given path.
@@ -102,6 +165,7 @@ fn write_default_configfile(path: &String) -> Result<(), std::io::Error> {
 
 /// General section of the config file.
 #[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 struct General {
     /// The username rusty-gopher will switch to after binding to a port < 1024.
     user: String,
@@ -109,6 +173,25 @@ struct General {
     rootdir: String,
     /// The listen address.
     listento: String,
+    /// Seconds after which a connection is dropped.
+    #[serde(default = "default_timeout")]
+    timeout: u64,
+    /// Seconds connections in progress may take to finish when shutting down.
+    #[serde(default = "default_shutdowntimeout")]
+    shutdowntimeout: u64,
+    /// Optional: File to write our process id to.
+    pidfile: Option<String>,
+    /// Deprecated: Log file, replaced by `logging.path`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    logfile: Option<String>,
+}
+
+fn default_timeout() -> u64 {
+    30
+}
+
+fn default_shutdowntimeout() -> u64 {
+    10
 }
 
 impl Default for General {
@@ -117,21 +200,740 @@ impl Default for General {
             user: "gopher".to_string(),
             rootdir: "/var/gopher".to_string(),
             listento: "0.0.0.0:70".to_string(),
+            timeout: default_timeout(),
+            shutdowntimeout: default_shutdowntimeout(),
+            pidfile: None,
+            logfile: None,
+        }
+    }
+}
+
+/// Markdown section of the config file.
+#[derive(Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Markdown {
+    /// Selectors of directories in which markdown files are rendered as
+    /// gophermap.
+    menudirs: Vec<String>,
+    /// Selectors of directories in which markdown files are rendered as plain
+    /// text.
+    textdirs: Vec<String>,
+    /// The column at which rendered text is wrapped.
+    width: usize,
+}
+
+impl Default for Markdown {
+    fn default() -> Self {
+        Markdown {
+            menudirs: Vec::new(),
+            textdirs: Vec::new(),
+            width: 70,
+        }
+    }
+}
+
+impl Markdown {
+    /// Returns the type a markdown file should be rendered as, or `None` if
+    /// `selector` doesn't point to a markdown file in one of the configured
+    /// directories.
+    fn rendering(&self, selector: &str) -> Option<GopherType> {
+        if !selector.ends_with(".md") {
+            return None;
+        }
+        let within = |dir: &String| {
+            selector.starts_with(&format!("{}/", dir.trim_end_matches('/')))
+        };
+        if self.menudirs.iter().any(&within) {
+            Some(GopherType::Directory)
+        } else if self.textdirs.iter().any(&within) {
+            Some(GopherType::File)
+        } else {
+            None
+        }
+    }
+}
+
+/// Logging section of the config file.
+#[derive(Serialize, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+struct Logging {
+    /// The least severe messages that are logged.
+    level: logging::Level,
+    /// Where messages are written to.
+    output: logging::Output,
+    /// Path of the log file if `output` is `file`.
+    path: Option<String>,
+    /// How messages are formatted.
+    format: logging::Format,
+    /// Levels of individual modules, overriding `level`.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    modules: BTreeMap<String, logging::Level>,
+}
+
+impl Default for Logging {
+    fn default() -> Self {
+        Logging {
+            level: logging::Level::default(),
+            output: logging::Output::default(),
+            path: None,
+            format: logging::Format::default(),
+            modules: BTreeMap::new(),
+        }
+    }
+}
+
+/// Limits section of the config file. Clients exceeding them are answered
+/// with an error.
+#[derive(Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Limits {
+    /// Optional: Connections served at the same time.
+    connections: Option<usize>,
+    /// Optional: Connections from the same address at the same time.
+    perip: Option<usize>,
+    /// Optional: Requests per minute from the same network.
+    perminute: Option<u32>,
+    /// Prefix length of the IPv4 networks counted by `perminute`.
+    ipv4prefix: u8,
+    /// Prefix length of the IPv6 networks counted by `perminute`.
+    ipv6prefix: u8,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            connections: None,
+            perip: None,
+            perminute: None,
+            ipv4prefix: 32,
+            ipv6prefix: 64,
+        }
+    }
+}
+
+/// Robots section of the config file, describing the generated robots.txt.
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Robots {
+    /// User agents the exclusions apply to.
+    #[serde(default = "default_useragent")]
+    useragent: String,
+    /// Selectors crawlers should leave alone.
+    #[serde(default)]
+    disallow: Vec<String>,
+}
+
+fn default_useragent() -> String {
+    "*".to_string()
+}
+
+/// Caps section of the config file, describing the generated caps.txt.
+#[derive(Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Caps {
+    /// Seconds after which clients should fetch caps.txt again.
+    expire: u64,
+    /// Encoding of our menus and text files.
+    encoding: String,
+    /// Optional: Contact of the administrator.
+    admin: Option<String>,
+    /// Optional: Description of the server.
+    description: Option<String>,
+    /// Optional: Where the server is located.
+    geolocation: Option<String>,
+}
+
+impl Default for Caps {
+    fn default() -> Self {
+        Caps {
+            expire: 3600,
+            encoding: "UTF-8".to_string(),
+            admin: None,
+            description: None,
+            geolocation: None,
         }
     }
 }
 
+/// Cache section of the config file. Menus and files are kept in memory
+/// until they are modified.
+#[derive(Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Cache {
+    /// Bytes of all cached items, 0 disables the cache.
+    size: usize,
+    /// Bytes of the largest item cached.
+    maxitem: usize,
+}
+
+impl Default for Cache {
+    fn default() -> Self {
+        Cache {
+            size: 16 * 1024 * 1024,
+            maxitem: 256 * 1024,
+        }
+    }
+}
+
+/// Access control rule of the config file or an override file. There may be
+/// any number of them, the first one matching a request applies.
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Acl {
+    /// Whether matching requests are allowed or denied.
+    action: acl::Action,
+    /// Glob pattern of the selectors the rule applies to. It is relative to
+    /// the directory in override files.
+    #[serde(default = "default_acl_selector")]
+    selector: String,
+    /// Networks of the clients the rule applies to, all if empty.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    networks: Vec<cidr::Network>,
+}
+
+fn default_acl_selector() -> String {
+    "**".to_string()
+}
+
+/// Phlog section of the config file. There may be any number of them.
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Phlog {
+    /// Selector of the directory containing the posts.
+    selector: String,
+    /// Title of the index and the atom feed.
+    title: String,
+    /// Number of posts on each index page.
+    #[serde(default = "default_perpage")]
+    perpage: usize,
+}
+
+fn default_perpage() -> usize {
+    10
+}
+
+/// HTTP gateway section of the config file.
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Http {
+    /// The listen address of the gateway.
+    listento: String,
+}
+
+/// Gemini section of the config file.
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Gemini {
+    /// The listen address of the gemini listener.
+    listento: String,
+    /// Path to the PEM encoded certificate (chain).
+    certificate: String,
+    /// Path to the PEM encoded PKCS#8 private key of the certificate.
+    key: String,
+}
+
+impl Gemini {
+    /// Reads certificate and key and creates a TLS acceptor from them.
+    fn tls_acceptor(&self) -> Result<native_tls::TlsAcceptor, String> {
+        let mut cert = Vec::new();
+        File::open(&self.certificate)
+            .and_then(|mut f| f.read_to_end(&mut cert))
+            .map_err(|e| format!("{}: {}", self.certificate, e))?;
+        let mut key = Vec::new();
+        File::open(&self.key)
+            .and_then(|mut f| f.read_to_end(&mut key))
+            .map_err(|e| format!("{}: {}", self.key, e))?;
+        let identity = native_tls::Identity::from_pkcs8(&cert, &key)
+            .map_err(|e| e.to_string())?;
+        native_tls::TlsAcceptor::new(identity).map_err(|e| e.to_string())
+    }
+}
+
+/// Finger section of the config file.
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Finger {
+    /// The listen address of the finger responder.
+    listento: String,
+    /// Selector of a directory containing a subdirectory with `.plan` and
+    /// `.project` for each user.
+    dir: Option<String>,
+    /// Whether to serve the `.plan` and `.project` files in the home
+    /// directories of system users.
+    #[serde(default)]
+    systemusers: bool,
+}
+
+/// Access log section of the config file.
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AccessLog {
+    /// Path of the access log.
+    path: String,
+    /// The format records are written in.
+    #[serde(default)]
+    format: accesslog::Format,
+}
+
+/// Metrics section of the config file.
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Metrics {
+    /// Optional: The listen address of the HTTP metrics endpoint.
+    listento: Option<String>,
+    /// Optional: The selector serving the metrics to gopher clients.
+    selector: Option<String>,
+}
+
+/// Server status section of the config file.
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct StatusPage {
+    /// Selector of the status menu, the other pages are below it.
+    #[serde(default = "default_status_prefix")]
+    prefix: String,
+    /// Optional: Networks of the clients allowed to see the status.
+    allow: Option<Vec<cidr::Network>>,
+}
+
+fn default_status_prefix() -> String {
+    "/server-status".to_string()
+}
+
 /// Config file struct.
 #[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 struct Config {
     /// General section.
     general: General,
+    /// Markdown rendering section.
+    #[serde(default)]
+    markdown: Markdown,
+    /// Logging section.
+    #[serde(default)]
+    logging: Logging,
+    /// Limits section.
+    #[serde(default)]
+    limits: Limits,
+    /// Cache section.
+    #[serde(default)]
+    cache: Cache,
+    /// Optional HTTP gateway section.
+    http: Option<Http>,
+    /// Optional gemini section.
+    gemini: Option<Gemini>,
+    /// Optional finger section.
+    finger: Option<Finger>,
+    /// Optional access log section.
+    accesslog: Option<AccessLog>,
+    /// Optional metrics section.
+    metrics: Option<Metrics>,
+    /// Optional server status section.
+    status: Option<StatusPage>,
+    /// Optional robots.txt section.
+    robots: Option<Robots>,
+    /// Optional caps.txt section.
+    caps: Option<Caps>,
+    /// Access control rules.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    acl: Vec<Acl>,
+    /// Phlog sections.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    phlog: Vec<Phlog>,
+    /// Contents cached in memory. Reloading the config starts with an empty
+    /// cache.
+    #[serde(skip)]
+    contents: cache::Cache,
+}
+
+impl Config {
+    /// Returns the selector the metrics are served at, if any.
+    fn metrics_selector(&self) -> Option<&String> {
+        self.metrics.as_ref().and_then(|m| m.selector.as_ref())
+    }
+
+    /// Returns the page of the server status `selector` requests, if it is
+    /// below the status prefix and `peer` may see it.
+    fn status_page<'a>(
+        &self,
+        selector: &'a str,
+        peer: &std::net::IpAddr,
+    ) -> Option<&'a str> {
+        let status = self.status.as_ref()?;
+        let base = status.prefix.trim_end_matches('/');
+        if selector != base && !selector.starts_with(&format!("{}/", base)) {
+            return None;
+        }
+        match status.allow {
+            Some(ref allow) if !cidr::Network::any_contains(allow, peer) => {
+                None
+            }
+            _ => Some(&selector[base.len()..]),
+        }
+    }
+
+    /// Returns the phlog `selector` belongs to and the generated page it
+    /// requests, if any.
+    fn phlog_request(
+        &self,
+        selector: &str,
+    ) -> Option<(&Phlog, phlog::Request)> {
+        self.phlog
+            .iter()
+            .filter_map(|p| {
+                let base = p.selector.trim_end_matches('/');
+                if selector != base
+                    && !selector.starts_with(&format!("{}/", base))
+                {
+                    return None;
+                }
+                phlog::Request::from_str(&selector[base.len()..])
+                    .map(|r| (p, r))
+            })
+            .next()
+    }
+}
+
+/// Checks that `addr` is a valid listen address.
+fn check_address(field: &str, addr: &str, problems: &mut Vec<String>) {
+    if let Err(e) = std::net::SocketAddr::from_str(addr) {
+        problems.push(format!(
+            "{}: \"{}\" is not a valid address of the form ip:port ({})",
+            field, addr, e
+        ));
+    }
+}
+
+/// Checks that `selector` points to a readable directory below `rootdir`.
+fn check_dir(
+    field: &str,
+    rootdir: &str,
+    selector: &str,
+    problems: &mut Vec<String>,
+) {
+    let path = format!("{}{}", rootdir, selector);
+    match std::fs::read_dir(&path) {
+        Ok(_) => {}
+        Err(e) => {
+            problems.push(format!("{}: cannot read \"{}\": {}", field, path, e))
+        }
+    }
+}
+
+/// Checks that the directory `path` is to be created in exists.
+fn check_parent(field: &str, path: &str, problems: &mut Vec<String>) {
+    let parent = match std::path::Path::new(path).parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => return,
+    };
+    if !parent.is_dir() {
+        problems.push(format!(
+            "{}: directory \"{}\" does not exist",
+            field,
+            parent.display()
+        ));
+    }
+}
+
+impl Config {
+    /// Reads the config file at `path`.
+    fn read(path: &str) -> Result<Config, String> {
+        let mut cfgstring = String::new();
+        File::open(path)
+            .and_then(|mut f| f.read_to_string(&mut cfgstring))
+            .map_err(|e| {
+                format!("Error opening configuration file at {}: {}", path, e)
+            })?;
+        let mut config: Config = toml::from_str(&cfgstring).map_err(|e| {
+            format!("Error parsing configuration file {}: {}", path, e)
+        })?;
+        // Config files written before the logging section keep working
+        if let Some(ref logfile) = config.general.logfile {
+            if config.logging.path.is_none() {
+                config.logging.output = logging::Output::File;
+                config.logging.path = Some(logfile.clone());
+            }
+        }
+        Ok(config)
+    }
+
+    /// Checks the configuration for everything that would stop us from
+    /// serving, returning a description of every problem found.
+    fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        let general = &self.general;
+
+        let mut addresses = vec![("general.listento", &general.listento)];
+        if let Some(ref http) = self.http {
+            addresses.push(("http.listento", &http.listento));
+        }
+        if let Some(ref gemini) = self.gemini {
+            addresses.push(("gemini.listento", &gemini.listento));
+        }
+        if let Some(ref finger) = self.finger {
+            addresses.push(("finger.listento", &finger.listento));
+        }
+        if let Some(Metrics { listento: Some(ref listento), .. }) = self.metrics
+        {
+            addresses.push(("metrics.listento", listento));
+        }
+        for (i, &(field, addr)) in addresses.iter().enumerate() {
+            check_address(field, addr, &mut problems);
+            if let Some(&(other, _)) =
+                addresses[..i].iter().find(|&&(_, a)| a == addr)
+            {
+                problems.push(format!(
+                    "{}: \"{}\" is already used by {}",
+                    field, addr, other
+                ));
+            }
+        }
+
+        check_dir("general.rootdir", &general.rootdir, "", &mut problems);
+        if get_user_by_name(&general.user).is_none() {
+            problems.push(format!(
+                "general.user: user \"{}\" does not exist",
+                general.user
+            ));
+        }
+        if general.timeout == 0 {
+            problems.push("general.timeout: must be at least 1".to_string());
+        }
+        if let Some(ref pidfile) = general.pidfile {
+            check_parent("general.pidfile", pidfile, &mut problems);
+        }
+        if general.logfile.is_some() && general.logfile != self.logging.path {
+            problems.push(
+                "general.logfile: conflicts with logging.path".to_string(),
+            );
+        }
+        match self.logging.path {
+            Some(ref path) => check_parent("logging.path", path, &mut problems),
+            None if self.logging.output == logging::Output::File => problems
+                .push("logging.path: required for output \"file\"".to_string()),
+            None => {}
+        }
+        if self.limits.ipv4prefix > 32 {
+            problems.push("limits.ipv4prefix: must be at most 32".to_string());
+        }
+        if self.limits.ipv6prefix > 128 {
+            problems
+                .push("limits.ipv6prefix: must be at most 128".to_string());
+        }
+        if self.markdown.width == 0 {
+            problems.push("markdown.width: must be at least 1".to_string());
+        }
+        for (field, dirs) in &[
+            ("markdown.menudirs", &self.markdown.menudirs),
+            ("markdown.textdirs", &self.markdown.textdirs),
+        ] {
+            for dir in dirs.iter() {
+                check_dir(field, &general.rootdir, dir, &mut problems);
+            }
+        }
+        for phlog in &self.phlog {
+            check_dir(
+                "phlog.selector",
+                &general.rootdir,
+                &phlog.selector,
+                &mut problems,
+            );
+            if phlog.perpage == 0 {
+                problems.push(format!(
+                    "phlog.perpage: must be at least 1 for {}",
+                    phlog.selector
+                ));
+            }
+        }
+        if let Some(Finger { dir: Some(ref dir), .. }) = self.finger {
+            check_dir("finger.dir", &general.rootdir, dir, &mut problems);
+        }
+        if let Some(ref accesslog) = self.accesslog {
+            check_parent("accesslog.path", &accesslog.path, &mut problems);
+        }
+        for acl in &self.acl {
+            if !acl.selector.starts_with('/') && !acl.selector.starts_with('*')
+            {
+                problems.push(format!(
+                    "acl.selector: \"{}\" does not start with \"/\" or \"*\"",
+                    acl.selector
+                ));
+            }
+        }
+        let mut selectors = Vec::new();
+        if let Some(Metrics { selector: Some(ref selector), .. }) = self.metrics
+        {
+            selectors.push(("metrics.selector", selector));
+        }
+        if let Some(ref status) = self.status {
+            selectors.push(("status.prefix", &status.prefix));
+        }
+        for (field, selector) in selectors {
+            if !selector.starts_with('/') {
+                problems.push(format!(
+                    "{}: \"{}\" does not start with \"/\"",
+                    field, selector
+                ));
+            }
+        }
+        problems
+    }
+
+    /// Returns a problem for every path which would change its meaning once
+    /// the working directory is `/`.
+    fn relative_paths(&self) -> Vec<String> {
+        let mut paths = vec![("general.rootdir", &self.general.rootdir)];
+        if let Some(ref pidfile) = self.general.pidfile {
+            paths.push(("general.pidfile", pidfile));
+        }
+        if let Some(ref path) = self.logging.path {
+            paths.push(("logging.path", path));
+        }
+        if let Some(ref accesslog) = self.accesslog {
+            paths.push(("accesslog.path", &accesslog.path));
+        }
+        paths
+            .into_iter()
+            .filter(|&(_, path)| std::path::Path::new(path).is_relative())
+            .map(|(field, path)| {
+                format!(
+                    "{}: \"{}\" has to be absolute with --daemon",
+                    field, path
+                )
+            })
+            .collect()
+    }
+
+    /// Like `validate`, but also checks the settings applied only when
+    /// starting, while we may still read files reserved to root.
+    fn validate_startup(&self) -> Vec<String> {
+        let mut problems = self.validate();
+        if let Some(ref gemini) = self.gemini {
+            if let Err(e) = gemini.tls_acceptor() {
+                problems.push(format!("gemini: unable to set up TLS: {}", e));
+            }
+        }
+        problems
+    }
+}
+
+/// The configuration in effect. Connections take a snapshot when they are
+/// accepted, so replacing it only affects new connections.
+#[derive(Clone)]
+struct ConfigHandle(Arc<RwLock<Arc<Config>>>);
+
+impl ConfigHandle {
+    fn new(config: Config) -> ConfigHandle {
+        ConfigHandle(Arc::new(RwLock::new(Arc::new(config))))
+    }
+
+    /// Returns the configuration in effect.
+    fn current(&self) -> Arc<Config> {
+        self.0.read().expect("config lock poisoned").clone()
+    }
+
+    /// Puts `config` into effect.
+    fn replace(&self, config: Config) {
+        *self.0.write().expect("config lock poisoned") = Arc::new(config);
+    }
+}
+
+/// Returns the settings which differ between `old` and `new` but only take
+/// effect after a restart, as they are applied before dropping privileges.
+fn restart_required(old: &Config, new: &Config) -> Vec<&'static str> {
+    let mut res = Vec::new();
+    if old.general.listento != new.general.listento {
+        res.push("general.listento");
+    }
+    if old.general.user != new.general.user {
+        res.push("general.user");
+    }
+    if old.http.as_ref().map(|h| &h.listento)
+        != new.http.as_ref().map(|h| &h.listento)
+    {
+        res.push("http");
+    }
+    if old.gemini.as_ref().map(|g| (&g.listento, &g.certificate, &g.key))
+        != new.gemini.as_ref().map(|g| (&g.listento, &g.certificate, &g.key))
+    {
+        res.push("gemini");
+    }
+    if old.finger.as_ref().map(|f| &f.listento)
+        != new.finger.as_ref().map(|f| &f.listento)
+    {
+        res.push("finger.listento");
+    }
+    if old.accesslog.as_ref().map(|a| (&a.path, a.format))
+        != new.accesslog.as_ref().map(|a| (&a.path, a.format))
+    {
+        res.push("accesslog");
+    }
+    if old.logging != new.logging {
+        res.push("logging");
+    }
+    if old.metrics.as_ref().and_then(|m| m.listento.as_ref())
+        != new.metrics.as_ref().and_then(|m| m.listento.as_ref())
+    {
+        res.push("metrics.listento");
+    }
+    res
+}
+
+/// Re-reads the config file at `path` and puts it into effect if it is valid.
+fn reload(path: &str, handle: &ConfigHandle, log: &slog::Logger) {
+    info!(log, "reloading configuration"; "path" => path);
+    let _ = systemd::notify("RELOADING=1");
+    reload_config(path, handle, log);
+    let _ = systemd::notify("READY=1");
+}
+
+/// Reads and validates the config file at `path`, replacing the one in
+/// `handle` if it is valid.
+fn reload_config(path: &str, handle: &ConfigHandle, log: &slog::Logger) {
+    let config = match Config::read(path) {
+        Ok(config) => config,
+        Err(e) => {
+            warn!(log, "keeping old configuration"; "error" => e);
+            return;
+        }
+    };
+    let problems = config.validate();
+    if !problems.is_empty() {
+        for problem in problems {
+            warn!(log, "invalid configuration"; "problem" => problem);
+        }
+        warn!(log, "keeping old configuration");
+        return;
+    }
+    if config.general.logfile.is_some() {
+        warn!(log, "general.logfile is deprecated, use logging.path");
+    }
+    for field in restart_required(&handle.current(), &config) {
+        warn!(log, "change requires a restart"; "setting" => field);
+    }
+    handle.replace(config);
+    info!(log, "configuration reloaded");
 }
 
 impl Default for Config {
     fn default() -> Self {
         Config {
             general: General::default(),
+            markdown: Markdown::default(),
+            logging: Logging::default(),
+            limits: Limits::default(),
+            cache: Cache::default(),
+            http: None,
+            gemini: None,
+            finger: None,
+            accesslog: None,
+            metrics: None,
+            status: None,
+            robots: None,
+            caps: None,
+            acl: Vec::new(),
+            phlog: Vec::new(),
+            contents: cache::Cache::new(),
         }
     }
 }
@@ -142,168 +944,919 @@ fn main() -> Result<(), std::io::Error> {
         .and_then(|d| d.deserialize())
         .unwrap_or_else(|e| e.exit());
 
+    if args.cmd_fetch {
+        return fetch(
+            &args.arg_url.unwrap_or_default(),
+            args.flag_raw,
+            args.flag_output,
+            Duration::from_secs(args.flag_timeout),
+        );
+    }
+
     // Either use the default config file path or the user supplied.
     let cfgpath = args.arg_config.unwrap_or(DEFAULT_MASTER_CONFIG.to_string());
 
     if args.cmd_genconfig {
-        write_default_configfile(&cfgpath)?;
+        return write_default_configfile(&cfgpath);
     }
 
-    let mut cfgfile = File::open(&cfgpath)
-        .expect(&format!("Error opening configuration file at: {}", cfgpath));
+    let config = match Config::read(&cfgpath) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(ExitCode::FAILURE.report());
+        }
+    };
+    if config.general.logfile.is_some() {
+        eprintln!(
+            "{}: general.logfile is deprecated, use logging.path",
+            cfgpath
+        );
+    }
 
-    let mut cfgstring = String::new();
-    cfgfile.read_to_string(&mut cfgstring)?;
-    let config: Config = toml::from_str(&cfgstring).unwrap();
+    if args.cmd_check_config || args.cmd_serve {
+        let mut problems = config.validate_startup();
+        if args.flag_daemon {
+            problems.extend(config.relative_paths());
+        }
+        for problem in &problems {
+            eprintln!("{}: {}", cfgpath, problem);
+        }
+        if !problems.is_empty() {
+            exit(ExitCode::FAILURE.report());
+        }
+        if args.cmd_check_config {
+            println!("{}: configuration is valid", cfgpath);
+            exit(ExitCode::SUCCESS.report());
+        }
+    }
 
-    let rtlog_decorator = slog_term::PlainSyncDecorator::new(std::io::stdout());
-    let rtlog = slog::Logger::root(
-        slog_term::FullFormat::new(rtlog_decorator).build().fuse(),
-        o!(env!("CARGO_PKG_NAME") => env!("CARGO_PKG_VERSION")),
-    );
+    if args.cmd_lint {
+        let findings = lint::lint(&config)?;
+        for finding in &findings {
+            println!("{}", finding);
+        }
+        if findings.iter().any(|f| f.severity == lint::Severity::Error) {
+            exit(ExitCode::FAILURE.report());
+        }
+        exit(ExitCode::SUCCESS.report());
+    }
+
+    if args.cmd_check_links {
+        let timeout = Duration::from_secs(args.flag_timeout);
+        let remote = if args.flag_remote { Some(timeout) } else { None };
+        let reports = linkcheck::check(&config, remote)?;
+        for report in &reports {
+            println!("{}", report);
+        }
+        let dead = reports.iter().any(|r| match r.status {
+            linkcheck::Status::Redirected(_) => false,
+            _ => true,
+        });
+        if dead {
+            exit(ExitCode::FAILURE.report());
+        }
+        exit(ExitCode::SUCCESS.report());
+    }
+
+    // The config file is read again on reload, after leaving the directory
+    let cfgpath = if args.flag_daemon {
+        std::fs::canonicalize(&cfgpath)?.display().to_string()
+    } else {
+        cfgpath
+    };
+
+    // A log file is still opened as root
+    let (rtlog, logfile) = match logging::root(&config.logging) {
+        Ok(res) => res,
+        Err(e) => {
+            eprintln!("Unable to set up logging: {}", e);
+            exit(ExitCode::FAILURE.report());
+        }
+    };
+    match config.logging.output {
+        logging::Output::Stdout | logging::Output::Stderr
+            if args.flag_daemon =>
+        {
+            eprintln!("Logging to a terminal, log messages are discarded");
+        }
+        _ => {}
+    }
 
-    match listen_and_serve(config, rtlog) {
+    let res =
+        listen_and_serve(config, cfgpath, rtlog, args.flag_daemon, logfile);
+    std::io::stdout().flush()?;
+    match res {
         Some(_) => exit(ExitCode::FAILURE.report()),
         None => exit(ExitCode::SUCCESS.report()),
     }
 }
 
+/// Fetches a gopher URL and prints the response. Menus and text documents are
+/// formatted for terminals unless `raw` is set.
+///
+/// # Arguments
+///
+/// * `url` - The gopher URL to fetch.
+/// * `raw` - Whether to print the response as received.
+/// * `output` - Optional: File to save the response to instead.
+/// * `timeout` - Time to wait for the server.
+fn fetch(
+    url: &str,
+    raw: bool,
+    output: Option<String>,
+    timeout: Duration,
+) -> Result<(), std::io::Error> {
+    let url = gopherurl::GopherUrl::parse(url).map_err(|e| {
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, e)
+    })?;
+    let addr = client::resolve(&url)?;
+    let data = tokio::runtime::Runtime::new()?
+        .block_on(client::fetch(&url, &addr, timeout))?;
+
+    if let Some(path) = output {
+        File::create(&path)?.write_all(&data)?;
+        eprintln!("Saved {} bytes to {}", data.len(), path);
+        return Ok(());
+    }
+    let stdout = std::io::stdout();
+    let mut stdout = stdout.lock();
+    match url.gtype {
+        _ if raw => stdout.write_all(&data),
+        // Search results are menus as well
+        GopherType::Directory | GopherType::Search => {
+            let map = client::parse_menu(&data, url.host.clone(), url.port);
+            stdout.write_all(client::format_menu(&map).as_bytes())
+        }
+        GopherType::File => {
+            stdout.write_all(client::strip_terminator(&data).as_bytes())
+        }
+        _ => stdout.write_all(&data),
+    }
+}
+
+/// Serves until we receive SIGTERM or SIGINT.
+///
+/// # Arguments
+///
+/// * `config` - The server configuration.
+/// * `cfgpath` - Path of the config file, read again on SIGHUP.
+/// * `rtlog` - The root logger.
+/// * `detach` - Whether to run as daemon once the sockets are bound.
+/// * `logfile` - Optional: The log file to reopen on SIGUSR1, along with the
+///   access log.
 fn listen_and_serve(
     config: Config,
+    cfgpath: String,
     rtlog: slog::Logger,
+    detach: bool,
+    logfile: Option<daemon::LogFile>,
 ) -> Option<std::io::Error> {
+    // Sockets passed by systemd take precedence over the configured ones
+    let mut sockets = systemd::Sockets::from_env();
+
     // Create tcp listener on provided address
     let addr = std::net::SocketAddr::from_str(&config.general.listento)
         .expect("Error reading \"listento\" value.\n");
-    let listener =
-        TcpListener::bind(&addr).expect("Unable to bind TcpListener");
+    let inherited = sockets
+        .take("gopher", &addr)
+        .or_else(|| sockets.take_unnamed());
+    let listener = match inherited {
+        Some(listener) => inherit_listener(listener),
+        None => bind(&mut sockets, "gopher", &addr),
+    };
+    let listener = match listener {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!(rtlog, "unable to listen"; "error" => %e);
+            return Some(e);
+        }
+    };
     let llog = rtlog.new(
         o!("local address" => format!("{}", listener.local_addr().unwrap())),
     );
     info!(llog, "listening");
 
-    // Setting desired uid
-    let desired = get_user_by_name(&config.general.user)?;
-    if desired.uid() != get_current_uid() {
-        users::switch::set_current_uid(desired.uid()).unwrap();
+    // The gateway has to be bound before we drop our privileges as well
+    let http = match config.http {
+        Some(ref http) => {
+            let haddr = std::net::SocketAddr::from_str(&http.listento)
+                .expect("Error reading \"http.listento\" value.\n");
+            let hlistener = match bind(&mut sockets, "http", &haddr) {
+                Ok(listener) => listener,
+                Err(e) => {
+                    error!(rtlog, "unable to listen"; "error" => %e);
+                    return Some(e);
+                }
+            };
+            let hlog = rtlog.new(o!("http address" => format!("{}", haddr)));
+            info!(hlog, "listening");
+            Some((hlistener, hlog))
+        }
+        None => None,
+    };
+    // Same goes for gemini, whose key is most likely only readable by root
+    let gemini = match config.gemini {
+        Some(ref gemini) => {
+            let gaddr = std::net::SocketAddr::from_str(&gemini.listento)
+                .expect("Error reading \"gemini.listento\" value.\n");
+            let acceptor = match gemini.tls_acceptor() {
+                Ok(acceptor) => acceptor,
+                Err(e) => {
+                    error!(rtlog, "unable to set up TLS for gemini";
+                           "error" => &e);
+                    return Some(std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        e,
+                    ));
+                }
+            };
+            let glistener = match bind(&mut sockets, "gemini", &gaddr) {
+                Ok(listener) => listener,
+                Err(e) => {
+                    error!(rtlog, "unable to listen"; "error" => %e);
+                    return Some(e);
+                }
+            };
+            let glog = rtlog.new(o!("gemini address" => format!("{}", gaddr)));
+            info!(glog, "listening");
+            Some((glistener, tokio_tls::TlsAcceptor::from(acceptor), glog))
+        }
+        None => None,
+    };
+    let finger = match config.finger {
+        Some(ref finger) => {
+            let faddr = std::net::SocketAddr::from_str(&finger.listento)
+                .expect("Error reading \"finger.listento\" value.\n");
+            let flistener = match bind(&mut sockets, "finger", &faddr) {
+                Ok(listener) => listener,
+                Err(e) => {
+                    error!(rtlog, "unable to listen"; "error" => %e);
+                    return Some(e);
+                }
+            };
+            let flog = rtlog.new(o!("finger address" => format!("{}", faddr)));
+            info!(flog, "listening");
+            Some((flistener, flog))
+        }
+        None => None,
+    };
+    let metrics = match config.metrics {
+        Some(Metrics { listento: Some(ref listento), .. }) => {
+            let maddr = std::net::SocketAddr::from_str(listento)
+                .expect("Error reading \"metrics.listento\" value.\n");
+            let mlistener = match bind(&mut sockets, "metrics", &maddr) {
+                Ok(listener) => listener,
+                Err(e) => {
+                    error!(rtlog, "unable to listen"; "error" => %e);
+                    return Some(e);
+                }
+            };
+            let mlog = rtlog.new(o!("metrics address" => format!("{}", maddr)));
+            info!(mlog, "listening");
+            Some((mlistener, mlog))
+        }
+        _ => None,
+    };
+    for unused in sockets.remaining() {
+        warn!(rtlog, "ignoring socket passed by systemd"; "address" => unused);
+    }
+
+    // The access log may be in a directory only root can write to
+    let accesslog = match config.accesslog {
+        Some(ref a) => match accesslog::AccessLog::open(&a.path, a.format) {
+            Ok(accesslog) => Some(accesslog),
+            Err(e) => {
+                error!(rtlog, "unable to open access log";
+                       "path" => &a.path, "error" => %e);
+                return Some(e);
+            }
+        },
+        None => None,
+    };
+
+    // Detach once binding can't fail anymore, but while we may still write
+    // the PID file
+    if detach {
+        if let Err(e) = daemon::daemonize() {
+            error!(rtlog, "unable to detach"; "error" => %e);
+            return Some(e);
+        }
+        info!(rtlog, "running in the background"; "pid" => std::process::id());
     }
+    let _pidfile = match config.general.pidfile {
+        Some(ref path) => match daemon::PidFile::create(path) {
+            Ok(pidfile) => Some(pidfile),
+            Err(e) => {
+                error!(rtlog, "unable to write PID file";
+                       "path" => path, "error" => %e);
+                return Some(e);
+            }
+        },
+        None => None,
+    };
 
-    // Still messy here but its something
-    let _server = listener
-        .incoming()
-        .map_err(|e| error!(rtlog, "accept failed = {:?}", e))
-        .for_each(|mut stream| {
-            let clog = llog.new(
-                o!("peer address" => format!("{}", stream.peer_addr().unwrap())),
+    // Setting desired uid, unless we have been started unprivileged
+    let desired = match get_user_by_name(&config.general.user) {
+        Some(user) => user,
+        None => {
+            error!(rtlog, "unknown user"; "user" => &config.general.user);
+            return Some(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                "unknown user",
+            ));
+        }
+    };
+    if get_current_uid() != 0 {
+        info!(rtlog, "running unprivileged, keeping user";
+              "uid" => get_current_uid());
+    } else if desired.uid() != get_current_uid() {
+        if let Err(e) = users::switch::set_current_uid(desired.uid()) {
+            error!(rtlog, "unable to switch user";
+                   "user" => &config.general.user, "error" => %e);
+            return Some(e);
+        }
+    }
+
+    let port = listener
+        .local_addr()
+        .map(|a| a.port())
+        .unwrap_or(addr.port());
+    let config = ConfigHandle::new(config);
+    let reloads = {
+        let config = config.clone();
+        let slog = rtlog.new(o!("signal" => "SIGHUP"));
+        let elog = slog.clone();
+        tokio_signal::unix::Signal::new(libc::SIGHUP)
+            .flatten_stream()
+            .for_each(move |_| {
+                reload(&cfgpath, &config, &slog);
+                Ok(())
+            })
+            .map_err(
+                move |e| error!(elog, "signal handling failed"; "error" => %e),
+            )
+    };
+    let logfiles: Vec<daemon::LogFile> = logfile
+        .into_iter()
+        .chain(accesslog.iter().map(|a| a.file().clone()))
+        .collect();
+    let reopens = if logfiles.is_empty() {
+        None
+    } else {
+        let slog = rtlog.new(o!("signal" => "SIGUSR1"));
+        let elog = slog.clone();
+        let reopens = tokio_signal::unix::Signal::new(libc::SIGUSR1)
+            .flatten_stream()
+            .for_each(move |_| {
+                for logfile in &logfiles {
+                    match logfile.reopen() {
+                        Ok(()) => info!(slog, "log file reopened";
+                                        "path" => logfile.path()),
+                        Err(e) => {
+                            error!(slog, "unable to reopen log file";
+                                   "path" => logfile.path(), "error" => %e)
+                        }
+                    }
+                }
+                Ok(())
+            })
+            .map_err(
+                move |e| error!(elog, "signal handling failed"; "error" => %e),
             );
+        Some(reopens)
+    };
+    let http = http.map(|(hlistener, hlog)| {
+        http::serve(hlistener, config.clone(), port, hlog)
+    });
+    let gemini = gemini.map(|(glistener, acceptor, glog)| {
+        gemini::serve(glistener, acceptor, config.clone(), port, glog)
+    });
+    let finger = finger.map(|(flistener, flog)| {
+        finger::serve(flistener, config.clone(), flog)
+    });
+    let registry = Arc::new(metrics::Registry::new());
+    let statistics = Arc::new(status::Statistics::new());
+    let limiter = Arc::new(ratelimit::Limiter::new());
+    let metrics = metrics.map(|(mlistener, mlog)| {
+        metrics::serve(mlistener, registry.clone(), config.clone(), mlog)
+    });
+
+    let slog = rtlog.clone();
+    let handle = config.clone();
+    let server = listener
+        .incoming()
+        .map_err(move |e| error!(rtlog, "accept failed = {:?}", e))
+        .for_each(move |stream| {
+            // The client is already gone if its address is unknown
+            let peer = match stream.peer_addr() {
+                Ok(peer) => peer,
+                Err(_) => return Ok(()),
+            };
+            let clog = llog.new(o!("peer address" => format!("{}", peer)));
             info!(clog, "new connection received");
+            let elog = clog.clone();
+            let config = config.current();
+            let timeout = Duration::from_secs(config.general.timeout);
+            let start = Instant::now();
+            if let Err(refusal) =
+                limiter.admit(&peer.ip(), &config.limits, start)
+            {
+                warn!(clog, "connection refused"; "reason" => %refusal);
+                registry.refused(refusal);
+                statistics.refused();
+                // Answer without waiting for the request, refused clients
+                // must not hold on to their connection
+                let response = error_response(&refusal.to_string());
+                let refused = tokio::io::write_all(stream, response)
+                    .and_then(|(stream, _)| tokio::io::shutdown(stream))
+                    .timeout(timeout)
+                    .then(|_| Ok(()));
+                tokio::spawn(refused);
+                return Ok(());
+            }
+            let limiter = limiter.clone();
+            let record = Arc::new(Mutex::new(accesslog::Record::new(peer)));
+            let request = record.clone();
+            let mut accesslog = accesslog.clone();
+            let registry = registry.clone();
+            registry.accepted();
+            let served = registry.clone();
+            let statistics = statistics.clone();
+            let id = statistics.accepted(peer);
+            let stats = statistics.clone();
 
-            // Read stream into buffer
-            let mut buf = String::new();
-            stream.read_to_string(&mut buf).unwrap();
-            // Splitting buf into lines an iterate over them
-            let input: Vec<_> = buf.lines().collect();
-            input.into_iter().for_each(|input| {
-                debug!(clog, "got input"; "bytes read" => input);
+            // Read the request line...
+            let conn = tokio::io::read_until(
+                BufReader::new(stream),
+                b'\n',
+                Vec::new(),
+            )
+            .timeout(timeout)
+            .map_err(content::timed_out)
+            .and_then(move |(stream, buf)| {
+                let input = String::from_utf8_lossy(&buf)
+                    .trim_end_matches(|c| c == '\r' || c == '\n')
+                    .to_string();
+                debug!(clog, "got input"; "bytes read" => &input);
                 // ...and match the parsed input to a request
-                match parse_input(input.to_string()).unwrap() {
-                    GopherMessage::ListDir(selector) => {
-                        info!(clog, "got directory list request"; "selector" => &selector);
-                        let listing = get_directory_listing(
-                            config.general.rootdir.clone(),
-                            selector,
-                        ).unwrap();
-                        for l in listing {
-                            stream
-                                .write_fmt(format_args!(
-                                    "{}{}\t{}\t{}\t{}\r\n",
-                                    l.gtype.to_type_string(),
-                                    l.description,
-                                    l.selector,
-                                    l.host,
-                                    l.port
-                                ))
-                                .unwrap();
+                let mut request = request.lock().expect("record lock poisoned");
+                let ip = peer.ip();
+                let message = parse_input(&input);
+                let search = match message {
+                    GopherMessage::SearchDir(_, ref search) => {
+                        Some(search.clone())
+                    }
+                    GopherMessage::ListDir(_) => None,
+                };
+                let action = match message {
+                    GopherMessage::ListDir(ref selector)
+                    | GopherMessage::SearchDir(ref selector, _) => {
+                        stats.requested(id, selector);
+                        acl::check(&config, selector, &ip).unwrap_or_else(|e| {
+                            warn!(clog, "unable to check access";
+                                  "error" => %e);
+                            acl::Action::Deny
+                        })
+                    }
+                };
+                let (response, gtype, status) = match message {
+                    GopherMessage::ListDir(ref selector)
+                    | GopherMessage::SearchDir(ref selector, _)
+                        if action == acl::Action::Deny =>
+                    {
+                        info!(clog, "access denied"; "selector" => selector);
+                        request.selector = Some(selector.clone());
+                        (
+                            error_response("Access denied").into(),
+                            GopherType::Error,
+                            accesslog::Status::Forbidden,
+                        )
+                    }
+                    GopherMessage::ListDir(ref selector)
+                        if config.metrics_selector() == Some(selector) =>
+                    {
+                        info!(clog, "got metrics request");
+                        request.selector = Some(selector.clone());
+                        let cache = config.contents.stats();
+                        let metrics = served.render(&cache).into_bytes();
+                        let status = accesslog::Status::Ok;
+                        (metrics.into(), GopherType::File, status)
+                    }
+                    GopherMessage::ListDir(ref selector)
+                        if config.status_page(selector, &ip).is_some() =>
+                    {
+                        info!(clog, "got status request";
+                              "selector" => selector);
+                        request.selector = Some(selector.clone());
+                        let page =
+                            config.status_page(selector, &ip).unwrap_or("");
+                        let prefix = config
+                            .status
+                            .as_ref()
+                            .map_or("", |s| s.prefix.as_str());
+                        let hostname =
+                            get_hostname().expect("Failed to get hostname");
+                        let cache = config.contents.stats();
+                        match stats.menu(prefix, page, &hostname, port, &cache)
+                        {
+                            Some(map) => (
+                                map.to_string().into_bytes().into(),
+                                GopherType::Directory,
+                                accesslog::Status::Ok,
+                            ),
+                            None => (
+                                error_response("Resource not found").into(),
+                                GopherType::Error,
+                                accesslog::Status::NotFound,
+                            ),
                         }
                     }
-                    GopherMessage::SearchDir(selector, search_string) => {
-                        debug!(clog, "got search request"; "selector" => selector);
+                    GopherMessage::ListDir(selector) => {
+                        info!(clog, "got request"; "selector" => &selector);
+                        let resolved =
+                            content::resolve(&config, &selector, port);
+                        let res = match resolved {
+                            Ok(content) => {
+                                let gtype = content.gtype();
+                                let status = accesslog::Status::Ok;
+                                (content.into_response(), gtype, status)
+                            }
+                            Err(e) => {
+                                warn!(clog, "request failed"; "selector" => &selector, "error" => %e);
+                                (
+                                    error_response("Resource not found").into(),
+                                    GopherType::Error,
+                                    accesslog::Status::NotFound,
+                                )
+                            }
+                        };
+                        request.selector = Some(selector);
+                        res
+                    }
+                    GopherMessage::SearchDir(selector, _) => {
+                        debug!(clog, "got search request";
+                               "selector" => &selector);
+                        request.selector = Some(selector);
+                        (
+                            error_response("Search is not supported").into(),
+                            GopherType::Error,
+                            accesslog::Status::BadRequest,
+                        )
                     }
                 };
-            });
-            return Ok(());
+                request.search = search;
+                request.gtype = Some(gtype);
+                request.status = status;
+                response.send(stream.into_inner(), timeout)
+                    .map(|(_, bytes)| bytes as usize)
+            })
+                .then(move |res| {
+                    let mut record =
+                        record.lock().expect("record lock poisoned").clone();
+                    record.duration = start.elapsed();
+                    match res {
+                        Ok(bytes) => record.bytes = bytes,
+                        Err(e) => {
+                            warn!(elog, "connection failed"; "error" => %e);
+                            record.status = if e.kind()
+                                == std::io::ErrorKind::TimedOut
+                            {
+                                accesslog::Status::Timeout
+                            } else {
+                                accesslog::Status::Failed
+                            };
+                        }
+                    }
+                    registry.finished(&record);
+                    statistics.finished(id, &record);
+                    limiter.release(&peer.ip());
+                    if let Some(ref mut accesslog) = accesslog {
+                        if let Err(e) = accesslog.log(&record) {
+                            warn!(elog, "unable to write access log";
+                                  "error" => %e);
+                        }
+                    }
+                    Ok(())
+                });
+            tokio::spawn(conn);
+            Ok(())
         });
-    None
+
+    // Stop accepting connections on SIGTERM or SIGINT
+    let shutdown = tokio_signal::unix::Signal::new(libc::SIGTERM)
+        .flatten_stream()
+        .select(tokio_signal::unix::Signal::new(libc::SIGINT).flatten_stream())
+        .into_future()
+        .map(|(signal, _)| signal.unwrap_or(0))
+        .map_err(|(e, _)| e)
+        .shared();
+    let mut rt = match tokio::runtime::Runtime::new() {
+        Ok(rt) => rt,
+        Err(e) => return Some(e),
+    };
+    rt.spawn(until_shutdown(reloads, &shutdown));
+    if let Some(reopens) = reopens {
+        rt.spawn(until_shutdown(reopens, &shutdown));
+    }
+    if let Some(http) = http {
+        rt.spawn(until_shutdown(http, &shutdown));
+    }
+    if let Some(gemini) = gemini {
+        rt.spawn(until_shutdown(gemini, &shutdown));
+    }
+    if let Some(finger) = finger {
+        rt.spawn(until_shutdown(finger, &shutdown));
+    }
+    if let Some(metrics) = metrics {
+        rt.spawn(until_shutdown(metrics, &shutdown));
+    }
+    rt.spawn(until_shutdown(server, &shutdown));
+    if let Some(interval) = systemd::watchdog_interval() {
+        let wlog = slog.clone();
+        let watchdog = tokio::timer::Interval::new_interval(interval)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))
+            .for_each(|_| systemd::notify("WATCHDOG=1"))
+            .map_err(move |e| error!(wlog, "watchdog failed"; "error" => %e));
+        rt.spawn(until_shutdown(watchdog, &shutdown));
+    }
+    if let Err(e) = systemd::notify("READY=1") {
+        warn!(slog, "unable to notify systemd"; "error" => %e);
+    }
+    match rt.block_on(shutdown) {
+        Ok(signal) => {
+            info!(slog, "shutting down"; "signal" => *signal);
+            let _ = systemd::notify("STOPPING=1");
+        }
+        Err(e) => {
+            error!(slog, "signal handling failed"; "error" => %*e);
+            return Some(std::io::Error::new(e.kind(), e.to_string()));
+        }
+    }
+
+    // Let the connections in progress finish until the deadline passes
+    let deadline =
+        Duration::from_secs(handle.current().general.shutdowntimeout);
+    let idle = rt.shutdown_on_idle();
+    let (done, finished) = std::sync::mpsc::channel();
+    std::thread::spawn(move || done.send(idle.wait()));
+    match finished.recv_timeout(deadline) {
+        Ok(_) => {
+            info!(slog, "all connections finished");
+            None
+        }
+        Err(_) => {
+            warn!(slog, "shutdown deadline passed, dropping connections");
+            Some(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                "connections still open at shutdown deadline",
+            ))
+        }
+    }
 }
 
+/// Makes a socket passed by systemd usable with tokio.
+fn inherit_listener(
+    listener: std::net::TcpListener,
+) -> Result<TcpListener, std::io::Error> {
+    listener.set_nonblocking(true)?;
+    TcpListener::from_std(listener, &tokio::reactor::Handle::default())
+}
+
+/// Returns the socket systemd passed for `name` or `addr`, binding a new one
+/// if there is none. Errors name the listener and address.
+fn bind(
+    sockets: &mut systemd::Sockets,
+    name: &str,
+    addr: &std::net::SocketAddr,
+) -> Result<TcpListener, std::io::Error> {
+    match sockets.take(name, addr) {
+        Some(listener) => inherit_listener(listener),
+        None => TcpListener::bind(addr),
+    }
+    .map_err(|e| {
+        std::io::Error::new(
+            e.kind(),
+            format!("unable to bind {} listener to {}: {}", name, addr, e),
+        )
+    })
+}
+
+/// Runs `task` until `shutdown` resolves.
+fn until_shutdown<F, S>(
+    task: F,
+    shutdown: &Shared<S>,
+) -> impl Future<Item = (), Error = ()>
+where
+    F: Future<Item = (), Error = ()>,
+    S: Future,
+{
+    task.select(shutdown.clone().then(|_| Ok(())))
+        .then(|_| Ok(()))
+}
+
+#[derive(Debug, PartialEq)]
 enum GopherMessage {
     ListDir(String),
     SearchDir(String, String),
 }
 
-fn get_directory_listing(
-    root: String,
-    request: String,
-) -> Result<Vec<DirectoryEntry>, std::io::Error> {
-    let rd = std::fs::read_dir(root + &request)?;
-    let hostname = get_hostname().expect("Failed to get hostname");
-    let mut res: Vec<DirectoryEntry> = Vec::new();
-
-    for possible_entry in rd {
-        let entry = possible_entry?;
-        // Creating desired directory entry
-        // Shouldnt matter assigning GopherType::Error as gtype, after diren only
-        // gets pushed into res when its a directory or file
-        let mut diren = DirectoryEntry {
-            gtype: GopherType::Error,
-            description: format!(
-                "{}",
-                entry.file_name().into_string().unwrap()
-            ), //TODO
-            selector: format!(
-                "{}",
-                entry
-                    .path()
-                    .to_str()
-                    .expect("selector has to be valid utf8")
-                    .to_string()
-            ),
-            host: hostname.clone(),
-            port: 7070, //TODO
-        };
+/// Builds a menu containing nothing but an error message.
+fn error_response(message: &str) -> Vec<u8> {
+    let mut map = Gophermap::new();
+    map.entries.push(DirectoryEntry::error(message.to_string()));
+    map.to_string().into_bytes()
+}
 
-        // If the entry is a directory...
-        if entry.file_type()?.is_dir() {
-            diren.gtype = GopherType::Directory;
-            res.push(diren);
-        } else if entry.file_type()?.is_file() {
-            diren.gtype = GopherType::BinaryFile;
-            res.push(diren);
+/// Parses a request line: a selector, optionally followed by a search string
+/// after a tab. Gopher+ requests are answered like plain gopher requests.
+fn parse_input(input: &str) -> GopherMessage {
+    let mut fields = input.splitn(3, '\t');
+    let selector = match fields.next() {
+        Some("") | None => "/".to_string(),
+        Some(selector) => selector.to_string(),
+    };
+    match fields.next() {
+        None => GopherMessage::ListDir(selector),
+        Some(gopherplus)
+            if gopherplus.starts_with(|c| c == '+' || c == '!' || c == '$') =>
+        {
+            GopherMessage::ListDir(selector)
         }
+        Some(search) => GopherMessage::SearchDir(selector, search.to_string()),
     }
-    Ok(res)
 }
 
-fn parse_input(input: String) -> Result<GopherMessage, &'static str> {
-    match input.as_str() {
-        "\r\n" => Ok(GopherMessage::ListDir('/'.to_string())),
-        _ => {
-            if input.is_empty() {
-                return Err("Invalid request");
-            }
-            let selector_and_search: Vec<&str> = input.split("\t").collect();
-            if selector_and_search.len() < 2 {
-                return Ok(GopherMessage::ListDir(
-                    selector_and_search[0].to_string(),
-                ));
-            }
-            let mut selector = String::new();
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-            //named!(parser,);
+    #[test]
+    fn test_validate() {
+        let mut config: Config = toml::from_str(
+            "[general]\n\
+             user = \"root\"\n\
+             rootdir = \"/nonexistent\"\n\
+             listento = \"localhost:70\"\n\
+             timeout = 0\n\
+             [http]\n\
+             listento = \"localhost:70\"\n",
+        )
+        .unwrap();
+        let problems = config.validate();
+        assert_eq!(problems.len(), 5);
+        assert!(problems[0].starts_with(
+            "general.listento: \"localhost:70\" is not a valid address"
+        ));
+        assert_eq!(
+            problems[2],
+            "http.listento: \"localhost:70\" is already used by \
+             general.listento"
+        );
+        assert!(problems[3].starts_with(
+            "general.rootdir: cannot read \"/nonexistent\""
+        ));
+        assert_eq!(problems[4], "general.timeout: must be at least 1");
 
-            //foo and bar or boo and char -> foo bar and boo char and or
-            //TODO: iterate over second half to parse all logical operators
-            Ok(GopherMessage::ListDir(selector))
+        config.general.rootdir = std::env::temp_dir().display().to_string();
+        config.general.listento = "127.0.0.1:70".to_string();
+        config.general.timeout = 30;
+        config.http = None;
+        assert_eq!(config.validate(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_unknown_fields() {
+        let general = "[general]\n\
+                       user = \"root\"\n\
+                       rootdir = \"/srv/gopher\"\n\
+                       listento = \"localhost:70\"\n";
+        assert!(toml::from_str::<Config>(general).is_ok());
+        for typo in &[
+            "timout = 30\n",
+            "[markdown]\nmenudir = [\"/phlog\"]\n",
+            "[cache]\nmaxitems = 1\n",
+            "[http]\nlistento = \"[::]:80\"\nlistent = \"\"\n",
+            "[[acl]]\naction = \"deny\"\nnetwork = []\n",
+            "[logs]\nlevel = \"info\"\n",
+        ] {
+            let config = format!("{}{}", general, typo);
+            assert!(toml::from_str::<Config>(&config).is_err(), "{}", typo);
         }
+
+        let default = toml::to_string(&Config::default()).unwrap();
+        assert!(toml::from_str::<Config>(&default).is_ok());
+    }
+
+    #[test]
+    fn test_reload_without_tls_key() {
+        let dir = std::env::temp_dir().join("rusty-gopher-reload-test");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("rusty-gopher.toml");
+        let path = path.to_str().unwrap();
+        // Dropped privileges leave the key unreadable
+        let config = format!(
+            "[general]\n\
+             user = \"root\"\n\
+             rootdir = \"{}\"\n\
+             listento = \"127.0.0.1:70\"\n\
+             [gemini]\n\
+             listento = \"127.0.0.1:1965\"\n\
+             certificate = \"/nonexistent/cert.pem\"\n\
+             key = \"/nonexistent/key.pem\"\n",
+            dir.display()
+        );
+        std::fs::write(path, config).unwrap();
+
+        let handle = ConfigHandle::new(Config::default());
+        let log = slog::Logger::root(slog::Discard, o!());
+        reload_config(path, &handle, &log);
+        let config = handle.current();
+        assert_eq!(config.general.rootdir, dir.display().to_string());
+        assert_eq!(config.validate_startup().len(), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_relative_paths() {
+        let mut config = Config::default();
+        config.general.rootdir = "/srv/gopher".to_string();
+        assert!(config.relative_paths().is_empty());
+        config.general.rootdir = "gopher".to_string();
+        config.general.pidfile = Some("run/rusty-gopher.pid".to_string());
+        config.logging.path = Some("/var/log/rusty-gopher.log".to_string());
+        assert_eq!(
+            config.relative_paths(),
+            vec![
+                "general.rootdir: \"gopher\" has to be absolute with --daemon",
+                "general.pidfile: \"run/rusty-gopher.pid\" has to be absolute \
+                 with --daemon",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_deprecated_logfile() {
+        let dir = std::env::temp_dir().join("rusty-gopher-logfile-test");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("rusty-gopher.toml");
+        let general = "[general]\n\
+                       user = \"root\"\n\
+                       rootdir = \"/srv/gopher\"\n\
+                       listento = \"localhost:70\"\n\
+                       logfile = \"/var/log/gopher.log\"\n";
+        std::fs::write(&path, general).unwrap();
+        let config = Config::read(path.to_str().unwrap()).unwrap();
+        assert_eq!(config.logging.output, logging::Output::File);
+        assert_eq!(
+            config.logging.path,
+            Some("/var/log/gopher.log".to_string())
+        );
+
+        let logging = "[logging]\npath = \"/var/log/other.log\"\n";
+        std::fs::write(&path, format!("{}{}", general, logging)).unwrap();
+        let config = Config::read(path.to_str().unwrap()).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        let conflict = "general.logfile: conflicts with logging.path";
+        assert!(config.validate().contains(&conflict.to_string()));
+    }
+
+    #[test]
+    fn test_restart_required() {
+        let old = Config::default();
+        let mut new = Config::default();
+        new.general.rootdir = "/srv/gopher".to_string();
+        assert!(restart_required(&old, &new).is_empty());
+
+        new.general.listento = "0.0.0.0:7070".to_string();
+        new.http = Some(Http {
+            listento: "0.0.0.0:80".to_string(),
+        });
+        assert_eq!(
+            restart_required(&old, &new),
+            vec!["general.listento", "http"]
+        );
+    }
+
+    #[test]
+    fn test_parse_input() {
+        let list = |s: &str| GopherMessage::ListDir(s.to_string());
+        assert_eq!(parse_input(""), list("/"));
+        assert_eq!(parse_input("/phlog"), list("/phlog"));
+        assert_eq!(parse_input("/phlog\t+"), list("/phlog"));
+        assert_eq!(parse_input("/phlog\t!"), list("/phlog"));
+        assert_eq!(
+            parse_input("/search\trust lang"),
+            GopherMessage::SearchDir(
+                "/search".to_string(),
+                "rust lang".to_string()
+            )
+        );
+        assert_eq!(
+            parse_input("\trust\t+"),
+            GopherMessage::SearchDir("/".to_string(), "rust".to_string())
+        );
     }
 }
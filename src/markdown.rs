@@ -0,0 +1,528 @@
+/*
+ *    Copyright (C) 2016-2018 Stefan Luecke
+ *                  2018 Nicolas Martin
+ *
+ *    This program is free software: you can redistribute it and/or modify
+ *    it under the terms of the GNU Affero General Public License as published
+ *    by the Free Software Foundation, either version 3 of the License, or
+ *    (at your option) any later version.
+ *
+ *    This program is distributed in the hope that it will be useful,
+ *    but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *    GNU Affero General Public License for more details.
+ *
+ *    You should have received a copy of the GNU Affero General Public License
+ *    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ *    Authors: Stefan Luecke <glaxx@glaxx.net>
+ *             Nicolas Martin <penguwingit@gmail.com>
+ */
+use directoryentry::DirectoryEntry;
+use gophermap::Gophermap;
+use gophertype::GopherType;
+use gopherurl::GopherUrl;
+use pulldown_cmark::{Event, Parser, Tag};
+
+/// A link found inside a markdown block.
+#[derive(Debug, PartialEq)]
+struct Link {
+    text: String,
+    dest: String,
+}
+
+/// A markdown block, flattened to what gopher is able to display.
+#[derive(Debug, PartialEq)]
+enum Block {
+    Heading(i32, String),
+    Paragraph {
+        prefix: String,
+        text: String,
+        links: Vec<Link>,
+    },
+    Code(String),
+    Rule,
+}
+
+/// Collects the blocks of a markdown document.
+fn parse_blocks(input: &str) -> Vec<Block> {
+    let mut blocks = Vec::new();
+    let mut text = String::new();
+    let mut links: Vec<Link> = Vec::new();
+    // Destination and text start offset of the link we are currently in
+    let mut link: Option<(String, usize)> = None;
+    // Nesting of lists, None for unordered lists
+    let mut lists: Vec<Option<usize>> = Vec::new();
+    let mut quote = 0;
+    let mut prefix = String::new();
+
+    for event in Parser::new(input) {
+        match event {
+            Event::Start(Tag::Item) => {
+                let indent = "  ".repeat(lists.len().saturating_sub(1));
+                prefix = match lists.last_mut() {
+                    Some(Some(n)) => {
+                        *n += 1;
+                        format!("{}{}. ", indent, *n - 1)
+                    }
+                    _ => format!("{}* ", indent),
+                };
+            }
+            Event::Start(Tag::List(start)) => {
+                flush_paragraph(
+                    &mut blocks,
+                    &mut prefix,
+                    &mut text,
+                    &mut links,
+                );
+                lists.push(start);
+            }
+            Event::End(Tag::List(_)) => {
+                lists.pop();
+            }
+            Event::Start(Tag::BlockQuote) => quote += 1,
+            Event::End(Tag::BlockQuote) => quote -= 1,
+            Event::Start(Tag::Link(dest, _))
+            | Event::Start(Tag::Image(dest, _)) => {
+                link = Some((dest.into_owned(), text.len()));
+            }
+            Event::End(Tag::Link(_, _)) | Event::End(Tag::Image(_, _)) => {
+                if let Some((dest, start)) = link.take() {
+                    let label = text[start..].trim().to_string();
+                    links.push(Link {
+                        text: if label.is_empty() {
+                            dest.clone()
+                        } else {
+                            label
+                        },
+                        dest: dest,
+                    });
+                }
+            }
+            Event::Start(Tag::Code) | Event::End(Tag::Code) => text.push('`'),
+            Event::Text(t) | Event::Html(t) | Event::InlineHtml(t) => {
+                text.push_str(&t)
+            }
+            Event::SoftBreak | Event::HardBreak => text.push(' '),
+            Event::End(Tag::Header(level)) => {
+                blocks.push(Block::Heading(level, text.trim().to_string()));
+                text.clear();
+            }
+            Event::End(Tag::CodeBlock(_)) => {
+                blocks.push(Block::Code(text.trim_end().to_string()));
+                text.clear();
+            }
+            Event::End(Tag::Rule) => blocks.push(Block::Rule),
+            Event::End(Tag::Paragraph) | Event::End(Tag::Item) => {
+                if quote > 0 {
+                    prefix = format!("{}{}", "> ".repeat(quote), prefix);
+                }
+                flush_paragraph(
+                    &mut blocks,
+                    &mut prefix,
+                    &mut text,
+                    &mut links,
+                );
+            }
+            _ => {}
+        }
+    }
+    flush_paragraph(&mut blocks, &mut prefix, &mut text, &mut links);
+    blocks
+}
+
+/// Pushes the collected text as paragraph, if there is any.
+fn flush_paragraph(
+    blocks: &mut Vec<Block>,
+    prefix: &mut String,
+    text: &mut String,
+    links: &mut Vec<Link>,
+) {
+    if text.trim().is_empty() && links.is_empty() {
+        return;
+    }
+    blocks.push(Block::Paragraph {
+        prefix: std::mem::replace(prefix, String::new()),
+        text: text.trim().to_string(),
+        links: std::mem::replace(links, Vec::new()),
+    });
+    text.clear();
+}
+
+/// Wraps `text` at word boundaries so that no line exceeds `width`
+/// characters, unless a single word is longer than that. The first line is
+/// prefixed with `prefix`, all following lines are indented to match it.
+fn wrap(text: &str, prefix: &str, width: usize) -> Vec<String> {
+    let indent = " ".repeat(prefix.chars().count());
+    let mut lines = Vec::new();
+    let mut line = prefix.to_string();
+    let mut empty = true;
+
+    for word in text.split_whitespace() {
+        let len = line.chars().count();
+        if !empty && len + 1 + word.chars().count() > width {
+            lines.push(line);
+            line = indent.clone();
+            empty = true;
+        }
+        if !empty {
+            line.push(' ');
+        }
+        line.push_str(word);
+        empty = false;
+    }
+    if !empty || lines.is_empty() {
+        lines.push(line);
+    }
+    lines
+}
+
+/// Underlines a heading the way plain text documents usually do.
+fn heading_lines(level: i32, text: &str, width: usize) -> Vec<String> {
+    let mut lines = wrap(text, "", width);
+    let len = lines.iter().map(|l| l.chars().count()).max().unwrap_or(0);
+    match level {
+        1 => lines.push("=".repeat(len)),
+        2 => lines.push("-".repeat(len)),
+        _ => {}
+    }
+    lines
+}
+
+/// Turns a link destination into a menu entry. Relative destinations are
+/// resolved against `base`, the selector of the rendered document, and local
+/// files get the type `gtype` returns for their selector.
+fn link_entry<F>(
+    link: &Link,
+    base: &str,
+    host: &str,
+    port: u16,
+    gtype: &F,
+) -> DirectoryEntry
+where
+    F: Fn(&str) -> GopherType,
+{
+    let mut entry = DirectoryEntry::new();
+    entry.description = link.text.clone();
+
+    if let Ok(url) = GopherUrl::parse(&link.dest) {
+        return url.to_entry(link.text.clone());
+    } else if link.dest.contains("://") || link.dest.starts_with("mailto:") {
+        // Other protocols are linked with URL: selectors
+        return DirectoryEntry::url(
+            link.text.clone(),
+            &link.dest,
+            host.to_string(),
+            port,
+        );
+    } else {
+        let selector = if link.dest.starts_with('/') {
+            link.dest.clone()
+        } else {
+            let dir = match base.rfind('/') {
+                Some(i) => &base[..i + 1],
+                None => "/",
+            };
+            format!("{}{}", dir, link.dest)
+        };
+        entry.selector = match normalize(&selector) {
+            Some(selector) => selector,
+            None => {
+                return DirectoryEntry::error(format!(
+                    "{} (outside of the data root)",
+                    link.text
+                ))
+            }
+        };
+        entry.gtype = match entry.selector.rfind('.') {
+            Some(i) if !entry.selector[i..].contains('/') => {
+                gtype(&entry.selector)
+            }
+            _ => GopherType::Directory,
+        };
+        entry.host = host.to_string();
+        entry.port = port;
+    }
+    entry
+}
+
+/// Resolves the `.` and `..` components of `selector`, as selectors
+/// containing them aren't served. Returns `None` if it leaves the root.
+fn normalize(selector: &str) -> Option<String> {
+    let mut parts: Vec<&str> = Vec::new();
+    for part in selector.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop()?;
+            }
+            part => parts.push(part),
+        }
+    }
+    let mut normalized = format!("/{}", parts.join("/"));
+    if selector.ends_with('/') && !parts.is_empty() {
+        normalized.push('/');
+    }
+    Some(normalized)
+}
+
+/// Expands the tabs of an informational line, as tabs separate the fields of
+/// a menu line.
+fn info(line: &str) -> DirectoryEntry {
+    let mut expanded = String::new();
+    for c in line.chars() {
+        if c == '\t' {
+            expanded.push(' ');
+            while expanded.chars().count() % 8 != 0 {
+                expanded.push(' ');
+            }
+        } else {
+            expanded.push(c);
+        }
+    }
+    DirectoryEntry::info(expanded)
+}
+
+/// Renders a markdown document as `Gophermap`. Headings and paragraphs become
+/// informational lines, links become menu entries following the paragraph
+/// they are contained in.
+///
+/// # Arguments
+///
+/// * `input` - The markdown document.
+/// * `selector` - The selector of the document, used to resolve relative links.
+/// * `host` - Host for local links.
+/// * `port` - Port for local links.
+/// * `width` - The column at which text gets wrapped.
+/// * `gtype` - Returns the type local files are served with.
+///
+/// # Examples
+///
+/// ```
+/// let m = markdown::to_gophermap("# Hello", "/index.md", "localhost", 70, 70,
+///                                |_| GopherType::File);
+/// ```
+pub fn to_gophermap<F>(
+    input: &str,
+    selector: &str,
+    host: &str,
+    port: u16,
+    width: usize,
+    gtype: F,
+) -> Gophermap
+where
+    F: Fn(&str) -> GopherType,
+{
+    let mut map = Gophermap::new();
+    let mut first = true;
+
+    for block in parse_blocks(input) {
+        let lines = match block {
+            Block::Heading(level, text) => heading_lines(level, &text, width),
+            Block::Code(code) => code.lines().map(|l| l.to_string()).collect(),
+            Block::Rule => vec!["-".repeat(width)],
+            Block::Paragraph {
+                prefix,
+                text,
+                links,
+            } => {
+                if !first && prefix.is_empty() {
+                    map.entries.push(DirectoryEntry::info(String::new()));
+                }
+                first = false;
+                if !text.is_empty() {
+                    for line in wrap(&text, &prefix, width) {
+                        map.entries.push(info(&line));
+                    }
+                }
+                for link in links {
+                    let entry = link_entry(&link, selector, host, port, &gtype);
+                    map.entries.push(entry);
+                }
+                continue;
+            }
+        };
+        if !first {
+            map.entries.push(DirectoryEntry::info(String::new()));
+        }
+        first = false;
+        for line in lines {
+            map.entries.push(info(&line));
+        }
+    }
+    map
+}
+
+/// Renders a markdown document as plain text wrapped at `width` columns.
+/// Links are numbered and listed as references at the end of the text.
+///
+/// # Examples
+///
+/// ```
+/// let t = markdown::to_text("Some *markdown*", 70);
+/// ```
+pub fn to_text(input: &str, width: usize) -> String {
+    let mut out: Vec<String> = Vec::new();
+    let mut refs: Vec<String> = Vec::new();
+
+    for block in parse_blocks(input) {
+        let lines = match block {
+            Block::Heading(level, text) => heading_lines(level, &text, width),
+            Block::Code(code) => {
+                code.lines().map(|l| format!("    {}", l)).collect()
+            }
+            Block::Rule => vec!["-".repeat(width)],
+            Block::Paragraph {
+                prefix,
+                mut text,
+                links,
+            } => {
+                for link in links {
+                    refs.push(format!("[{}] {}", refs.len() + 1, link.dest));
+                    text.push_str(&format!(" [{}]", refs.len()));
+                }
+                if !out.is_empty() && prefix.is_empty() {
+                    out.push(String::new());
+                }
+                out.extend(wrap(&text, &prefix, width));
+                continue;
+            }
+        };
+        if !out.is_empty() {
+            out.push(String::new());
+        }
+        out.extend(lines);
+    }
+
+    if !refs.is_empty() {
+        out.push(String::new());
+        out.extend(refs);
+    }
+    let mut res = out.join("\r\n");
+    res.push_str("\r\n");
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wrap() {
+        assert_eq!(
+            wrap("the quick brown fox jumps", "* ", 12),
+            vec!["* the quick", "  brown fox", "  jumps"]
+        );
+        assert_eq!(wrap("", "", 10), vec![""]);
+        assert_eq!(wrap("overlongword x", "", 5), vec!["overlongword", "x"]);
+    }
+
+    #[test]
+    fn test_to_text() {
+        let md = "# Title\n\nSome [link](gopher://example.org/) here.\n\n\
+                  - one\n- two\n";
+        assert_eq!(
+            to_text(md, 70),
+            "Title\r\n=====\r\n\r\nSome link here. [1]\r\n* one\r\n* two\r\n\
+             \r\n[1] gopher://example.org/\r\n"
+        );
+    }
+
+    #[test]
+    fn test_to_gophermap() {
+        let md = "## News\n\nRead [the post](post.md) or \
+                  [floodgap](gopher://gopher.floodgap.com:70/1/world) or \
+                  [the web](https://www.rust-lang.org/).";
+        let gtype = |s: &str| match s {
+            "/phlog/post.md" => GopherType::Directory,
+            _ => GopherType::File,
+        };
+        let map =
+            to_gophermap(md, "/phlog/index.md", "localhost", 7070, 70, gtype);
+        let types: Vec<&GopherType> =
+            map.entries.iter().map(|e| &e.gtype).collect();
+        assert_eq!(
+            types,
+            vec![
+                &GopherType::Informational,
+                &GopherType::Informational,
+                &GopherType::Informational,
+                &GopherType::Informational,
+                &GopherType::Directory,
+                &GopherType::Directory,
+                &GopherType::Html,
+            ]
+        );
+        assert_eq!(map.entries[0].description, "News");
+        assert_eq!(map.entries[1].description, "----");
+        assert_eq!(map.entries[4].selector, "/phlog/post.md");
+        assert_eq!(map.entries[4].host, "localhost");
+        assert_eq!(map.entries[4].port, 7070);
+        assert_eq!(map.entries[5].selector, "/world");
+        assert_eq!(map.entries[5].host, "gopher.floodgap.com");
+        assert_eq!(map.entries[5].port, 70);
+        assert_eq!(map.entries[6].selector, "URL:https://www.rust-lang.org/");
+        assert_eq!(map.entries[6].host, "localhost");
+
+        let map = to_gophermap(
+            "[x](ééééé.md)",
+            "/a.md",
+            "localhost",
+            70,
+            70,
+            |_| GopherType::File,
+        );
+        let link = map
+            .entries
+            .iter()
+            .find(|e| e.gtype == GopherType::File)
+            .unwrap();
+        assert_eq!(link.selector, "/ééééé.md");
+    }
+
+    #[test]
+    fn test_to_gophermap_parent_links() {
+        let md = "[up](../about.md) [home](../..) [out](../../../etc/passwd) \
+                  [here](./a/../b/)";
+        let map = to_gophermap(
+            md,
+            "/phlog/2018/post.md",
+            "localhost",
+            70,
+            70,
+            |_| GopherType::File,
+        );
+        let links: Vec<(&GopherType, &str)> = map
+            .entries
+            .iter()
+            .skip(1)
+            .map(|e| (&e.gtype, e.selector.as_str()))
+            .collect();
+        assert_eq!(
+            links,
+            vec![
+                (&GopherType::File, "/phlog/about.md"),
+                (&GopherType::Directory, "/"),
+                (&GopherType::Error, ""),
+                (&GopherType::Directory, "/phlog/2018/b/"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_to_gophermap_code_tabs() {
+        let md = "Code:\n\n```\nif x {\n\treturn;\n}\nkey\tvalue\n```\n";
+        let map = to_gophermap(md, "/index.md", "localhost", 70, 70, |_| {
+            GopherType::File
+        });
+        let lines: Vec<&str> =
+            map.entries.iter().map(|e| e.description.as_str()).collect();
+        assert_eq!(
+            lines,
+            vec!["Code:", "", "if x {", "    return;", "}", "key     value"]
+        );
+        for entry in &map.entries {
+            assert_eq!(entry.to_string().split('\t').count(), 4);
+        }
+    }
+}
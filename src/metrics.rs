@@ -0,0 +1,398 @@
+/*
+ *    Copyright (C) 2016-2018 Stefan Luecke
+ *                  2018 Nicolas Martin
+ *
+ *    This program is free software: you can redistribute it and/or modify
+ *    it under the terms of the GNU Affero General Public License as published
+ *    by the Free Software Foundation, either version 3 of the License, or
+ *    (at your option) any later version.
+ *
+ *    This program is distributed in the hope that it will be useful,
+ *    but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *    GNU Affero General Public License for more details.
+ *
+ *    You should have received a copy of the GNU Affero General Public License
+ *    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ *    Authors: Stefan Luecke <glaxx@glaxx.net>
+ *             Nicolas Martin <penguwingit@gmail.com>
+ */
+//! Traffic metrics of the gopher server in the Prometheus text format.
+use accesslog::{Record, Status};
+use cache;
+use http;
+use ratelimit::Refusal;
+use slog;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::io::BufReader;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+use tokio;
+use tokio::net::TcpListener;
+use tokio::prelude::*;
+use ConfigHandle;
+
+/// Upper bounds of the request duration histogram buckets in seconds.
+const BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Prefix of all metric names.
+const PREFIX: &str = "rusty_gopher";
+
+#[derive(Default)]
+struct Counters {
+    connections: u64,
+    active: u64,
+    /// Requests answered, by item type.
+    requests: BTreeMap<String, u64>,
+    bytes: u64,
+    /// Failed requests, by status.
+    errors: BTreeMap<String, u64>,
+    /// Refused connections, by the limit they exceeded.
+    refused: BTreeMap<String, u64>,
+    /// Requests per duration bucket, the last one is `+Inf`.
+    durations: [u64; 12],
+    duration_sum: f64,
+}
+
+/// The metrics of all gopher connections.
+#[derive(Default)]
+pub struct Registry {
+    counters: Mutex<Counters>,
+}
+
+impl Registry {
+    pub fn new() -> Registry {
+        Registry::default()
+    }
+
+    fn counters(&self) -> MutexGuard<'_, Counters> {
+        self.counters.lock().expect("metrics lock poisoned")
+    }
+
+    /// Counts a connection accepted just now.
+    pub fn accepted(&self) {
+        let mut counters = self.counters();
+        counters.connections += 1;
+        counters.active += 1;
+    }
+
+    /// Counts a connection refused for exceeding a limit.
+    pub fn refused(&self, refusal: Refusal) {
+        *self
+            .counters()
+            .refused
+            .entry(refusal.limit().to_string())
+            .or_insert(0) += 1;
+    }
+
+    /// Counts the request a connection was closed after.
+    pub fn finished(&self, record: &Record) {
+        let mut counters = self.counters();
+        counters.active = counters.active.saturating_sub(1);
+        if let Some(ref gtype) = record.gtype {
+            *counters.requests.entry(gtype.to_string()).or_insert(0) += 1;
+        }
+        if record.status != Status::Ok {
+            *counters
+                .errors
+                .entry(record.status.to_string())
+                .or_insert(0) += 1;
+        }
+        counters.bytes += record.bytes as u64;
+
+        let secs = record.duration.as_secs() as f64
+            + f64::from(record.duration.subsec_nanos()) / 1e9;
+        let bucket = BUCKETS
+            .iter()
+            .position(|&le| secs <= le)
+            .unwrap_or(BUCKETS.len());
+        counters.durations[bucket] += 1;
+        counters.duration_sum += secs;
+    }
+
+    /// Renders all metrics and those of the content `cache` in the
+    /// Prometheus text format.
+    pub fn render(&self, cache: &cache::Stats) -> String {
+        let counters = self.counters();
+        let mut res = String::new();
+
+        header(
+            &mut res,
+            "connections_total",
+            "counter",
+            "Connections accepted.",
+        );
+        let _ = writeln!(
+            res,
+            "{}_connections_total {}",
+            PREFIX, counters.connections
+        );
+        header(
+            &mut res,
+            "active_connections",
+            "gauge",
+            "Connections in progress.",
+        );
+        let _ =
+            writeln!(res, "{}_active_connections {}", PREFIX, counters.active);
+        header(
+            &mut res,
+            "requests_total",
+            "counter",
+            "Requests answered by item type.",
+        );
+        for (gtype, count) in &counters.requests {
+            let _ = writeln!(
+                res,
+                "{}_requests_total{{type=\"{}\"}} {}",
+                PREFIX, gtype, count
+            );
+        }
+        header(
+            &mut res,
+            "sent_bytes_total",
+            "counter",
+            "Bytes of responses sent.",
+        );
+        let _ = writeln!(res, "{}_sent_bytes_total {}", PREFIX, counters.bytes);
+        header(
+            &mut res,
+            "errors_total",
+            "counter",
+            "Failed requests by kind.",
+        );
+        for (kind, count) in &counters.errors {
+            let _ = writeln!(
+                res,
+                "{}_errors_total{{kind=\"{}\"}} {}",
+                PREFIX, kind, count
+            );
+        }
+        header(
+            &mut res,
+            "refused_connections_total",
+            "counter",
+            "Connections refused by the limit they exceeded.",
+        );
+        for (limit, count) in &counters.refused {
+            let _ = writeln!(
+                res,
+                "{}_refused_connections_total{{limit=\"{}\"}} {}",
+                PREFIX, limit, count
+            );
+        }
+
+        header(
+            &mut res,
+            "request_duration_seconds",
+            "histogram",
+            "Time from accepting a connection until the response was sent.",
+        );
+        let mut cumulative = 0;
+        for (i, count) in counters.durations.iter().enumerate() {
+            cumulative += count;
+            let le = BUCKETS
+                .get(i)
+                .map_or("+Inf".to_string(), |le| le.to_string());
+            let _ = writeln!(
+                res,
+                "{}_request_duration_seconds_bucket{{le=\"{}\"}} {}",
+                PREFIX, le, cumulative
+            );
+        }
+        let _ = writeln!(
+            res,
+            "{}_request_duration_seconds_sum {}",
+            PREFIX, counters.duration_sum
+        );
+        let _ = writeln!(
+            res,
+            "{}_request_duration_seconds_count {}",
+            PREFIX, cumulative
+        );
+
+        header(
+            &mut res,
+            "cache_hits_total",
+            "counter",
+            "Requests answered from the content cache.",
+        );
+        let _ = writeln!(res, "{}_cache_hits_total {}", PREFIX, cache.hits);
+        header(
+            &mut res,
+            "cache_misses_total",
+            "counter",
+            "Requests the content had to be loaded for.",
+        );
+        let _ = writeln!(res, "{}_cache_misses_total {}", PREFIX, cache.misses);
+        header(&mut res, "cache_entries", "gauge", "Items in the cache.");
+        let _ = writeln!(res, "{}_cache_entries {}", PREFIX, cache.entries);
+        header(
+            &mut res,
+            "cache_bytes",
+            "gauge",
+            "Size of the items in the cache.",
+        );
+        let _ = writeln!(res, "{}_cache_bytes {}", PREFIX, cache.size);
+        res
+    }
+}
+
+/// Appends the help and type lines of the metric `name`.
+fn header(res: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(res, "# HELP {}_{} {}", PREFIX, name, help);
+    let _ = writeln!(res, "# TYPE {}_{} {}", PREFIX, name, kind);
+}
+
+/// Answers a single HTTP request line, serving the metrics at `/metrics`.
+fn handle(registry: &Registry, cache: &cache::Stats, line: &str) -> Vec<u8> {
+    let text = || ("Content-Type", "text/plain; version=0.0.4".to_string());
+    let parts: Vec<&str> = line.split_whitespace().collect();
+    if parts.len() < 2 {
+        return http::response(
+            "400 Bad Request",
+            &[text()],
+            b"Bad Request\n".to_vec(),
+            false,
+        );
+    }
+    let head = parts[0] == "HEAD";
+    if parts[0] != "GET" && !head {
+        return http::response(
+            "405 Method Not Allowed",
+            &[text(), ("Allow", "GET, HEAD".to_string())],
+            b"Method Not Allowed\n".to_vec(),
+            false,
+        );
+    }
+    if parts[1].split('?').next() != Some("/metrics") {
+        return http::response(
+            "404 Not Found",
+            &[text()],
+            b"Not Found\n".to_vec(),
+            head,
+        );
+    }
+    let metrics = registry.render(cache).into_bytes();
+    http::response("200 OK", &[text()], metrics, head)
+}
+
+/// Returns a future serving the metrics over HTTP to connections accepted on
+/// `listener`.
+///
+/// # Arguments
+///
+/// * `listener` - The bound metrics listener.
+/// * `registry` - The metrics to serve.
+/// * `config` - The server configuration.
+/// * `log` - Logger for this listener.
+pub(crate) fn serve(
+    listener: TcpListener,
+    registry: Arc<Registry>,
+    config: ConfigHandle,
+    log: slog::Logger,
+) -> impl Future<Item = (), Error = ()> {
+    let alog = log.clone();
+    listener
+        .incoming()
+        .map_err(move |e| error!(alog, "accept failed = {:?}", e))
+        .for_each(move |stream| {
+            let elog = log.clone();
+            let registry = registry.clone();
+            let config = config.current();
+            let timeout = Duration::from_secs(config.general.timeout);
+            let conn = tokio::io::read_until(
+                BufReader::new(stream),
+                b'\n',
+                Vec::new(),
+            )
+            .and_then(move |(stream, line)| {
+                let line = String::from_utf8_lossy(&line).into_owned();
+                let cache = config.contents.stats();
+                let res = handle(&registry, &cache, line.trim());
+                tokio::io::write_all(stream.into_inner(), res)
+            })
+            .timeout(timeout)
+            .map(|_| ())
+            .map_err(move |e| warn!(elog, "connection failed"; "error" => %e));
+            tokio::spawn(conn);
+            Ok(())
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gophertype::GopherType;
+
+    #[test]
+    fn test_render() {
+        let registry = Registry::new();
+        let mut record = Record::new("127.0.0.1:51234".parse().unwrap());
+        registry.accepted();
+        registry.accepted();
+        record.gtype = Some(GopherType::Directory);
+        record.status = Status::Ok;
+        record.bytes = 100;
+        record.duration = Duration::from_millis(20);
+        registry.finished(&record);
+        record.gtype = Some(GopherType::Error);
+        record.status = Status::NotFound;
+        record.bytes = 30;
+        record.duration = Duration::from_secs(20);
+        registry.finished(&record);
+        registry.accepted();
+        registry.refused(Refusal::PerIp);
+
+        let cache = cache::Stats {
+            entries: 2,
+            size: 300,
+            hits: 5,
+            misses: 2,
+        };
+        let metrics = registry.render(&cache);
+        for line in &[
+            "# TYPE rusty_gopher_connections_total counter",
+            "rusty_gopher_connections_total 3",
+            "rusty_gopher_active_connections 1",
+            "rusty_gopher_requests_total{type=\"1\"} 1",
+            "rusty_gopher_requests_total{type=\"3\"} 1",
+            "rusty_gopher_sent_bytes_total 130",
+            "rusty_gopher_errors_total{kind=\"not-found\"} 1",
+            "rusty_gopher_refused_connections_total{limit=\"perip\"} 1",
+            "rusty_gopher_request_duration_seconds_bucket{le=\"0.01\"} 0",
+            "rusty_gopher_request_duration_seconds_bucket{le=\"0.025\"} 1",
+            "rusty_gopher_request_duration_seconds_bucket{le=\"10\"} 1",
+            "rusty_gopher_request_duration_seconds_bucket{le=\"+Inf\"} 2",
+            "rusty_gopher_request_duration_seconds_count 2",
+            "rusty_gopher_cache_hits_total 5",
+            "rusty_gopher_cache_misses_total 2",
+            "rusty_gopher_cache_entries 2",
+            "rusty_gopher_cache_bytes 300",
+        ] {
+            assert!(metrics.lines().any(|l| l == *line), "missing {}", line);
+        }
+    }
+
+    #[test]
+    fn test_handle() {
+        let registry = Registry::new();
+        let cache = cache::Stats::default();
+        let res = String::from_utf8(handle(
+            &registry,
+            &cache,
+            "GET /metrics HTTP/1.0",
+        ))
+        .unwrap();
+        assert!(res.starts_with("HTTP/1.0 200 OK\r\n"));
+        assert!(res.contains("rusty_gopher_connections_total 0\n"));
+        let res =
+            String::from_utf8(handle(&registry, &cache, "GET / HTTP/1.0"))
+                .unwrap();
+        assert!(res.starts_with("HTTP/1.0 404 Not Found\r\n"));
+    }
+}
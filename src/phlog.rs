@@ -0,0 +1,586 @@
+/*
+ *    Copyright (C) 2016-2018 Stefan Luecke
+ *                  2018 Nicolas Martin
+ *
+ *    This program is free software: you can redistribute it and/or modify
+ *    it under the terms of the GNU Affero General Public License as published
+ *    by the Free Software Foundation, either version 3 of the License, or
+ *    (at your option) any later version.
+ *
+ *    This program is distributed in the hope that it will be useful,
+ *    but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *    GNU Affero General Public License for more details.
+ *
+ *    You should have received a copy of the GNU Affero General Public License
+ *    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ *    Authors: Stefan Luecke <glaxx@glaxx.net>
+ *             Nicolas Martin <penguwingit@gmail.com>
+ */
+use super::std;
+use directoryentry::DirectoryEntry;
+use gophermap::Gophermap;
+use gophertype::GopherType;
+use gopherurl::GopherUrl;
+use std::io::{BufRead, BufReader};
+
+/// Selector of the atom feed, relative to the phlog directory.
+pub const FEED: &'static str = "atom.xml";
+
+/// Number of posts the atom feed contains.
+const FEED_ENTRIES: usize = 20;
+
+const MONTHS: [&'static str; 12] = [
+    "January",
+    "February",
+    "March",
+    "April",
+    "May",
+    "June",
+    "July",
+    "August",
+    "September",
+    "October",
+    "November",
+    "December",
+];
+
+/// The date a post was published at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Date {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+}
+
+impl std::fmt::Display for Date {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{:04}-{:02}-{:02}", self.year, self.month, self.day)
+    }
+}
+
+impl Date {
+    /// Parses a date from the `YYYY-MM-DD` prefix of a file name.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let d = Date::from_file_name("2018-06-01-hello-world.md");
+    /// ```
+    pub fn from_file_name(name: &str) -> Option<Date> {
+        if name.len() < 10 || !name.is_char_boundary(10) {
+            return None;
+        }
+        let parts: Vec<&str> = name[..10].split('-').collect();
+        if parts.len() != 3 || parts[0].len() != 4 {
+            return None;
+        }
+        let date = Date {
+            year: parts[0].parse().ok()?,
+            month: parts[1].parse().ok()?,
+            day: parts[2].parse().ok()?,
+        };
+        if date.month < 1
+            || date.month > 12
+            || date.day < 1
+            || date.day > days_in_month(date.year, date.month)
+        {
+            return None;
+        }
+        Some(date)
+    }
+}
+
+/// Returns the number of days `month` of `year` has.
+fn days_in_month(year: u16, month: u8) -> u8 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// A single phlog post.
+#[derive(Debug)]
+pub struct Post {
+    pub date: Date,
+    pub title: String,
+    pub selector: String,
+    pub gtype: GopherType,
+}
+
+/// The different pages the phlog engine generates.
+#[derive(Debug, PartialEq)]
+pub enum Request {
+    /// The reverse-chronological index, starting at page 1.
+    Index(usize),
+    /// The archive, optionally narrowed down to a year and a month.
+    Archive(Option<u16>, Option<u8>),
+    /// The atom feed.
+    Feed,
+}
+
+impl Request {
+    /// Parses the part of a selector following the phlog directory. Returns
+    /// `None` if the selector doesn't point to a generated page.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// assert_eq!(Request::from_str("page/2"), Some(Request::Index(2)));
+    /// ```
+    pub fn from_str(s: &str) -> Option<Request> {
+        let parts: Vec<&str> = s.split('/').filter(|p| !p.is_empty()).collect();
+        match parts.as_slice() {
+            [] => Some(Request::Index(1)),
+            ["page", n] => match n.parse() {
+                Ok(n) if n > 0 => Some(Request::Index(n)),
+                _ => None,
+            },
+            [f] if *f == FEED => Some(Request::Feed),
+            ["archive"] => Some(Request::Archive(None, None)),
+            ["archive", y] => {
+                Some(Request::Archive(Some(y.parse().ok()?), None))
+            }
+            ["archive", y, m] => Some(Request::Archive(
+                Some(y.parse().ok()?),
+                Some(m.parse().ok()?),
+            )),
+            _ => None,
+        }
+    }
+}
+
+/// Reads the title of a post from its first non-empty line, stripping
+/// markdown heading markers.
+fn read_title(path: &std::path::Path) -> Option<String> {
+    let file = std::fs::File::open(path).ok()?;
+    for line in BufReader::new(file).lines().take(10) {
+        let line = line.ok()?;
+        let title = line.trim_start_matches('#').trim();
+        if !title.is_empty() {
+            return Some(title.to_string());
+        }
+    }
+    None
+}
+
+/// Reads all dated posts in `path`, newest first. Files not starting with a
+/// `YYYY-MM-DD` date are ignored.
+///
+/// # Arguments
+///
+/// * `path` - The phlog directory.
+/// * `selector` - The selector of the phlog directory.
+/// * `gtype` - Determines the gopher type of a post from its selector.
+pub fn read_posts<F>(
+    path: &std::path::Path,
+    selector: &str,
+    gtype: F,
+) -> Result<Vec<Post>, std::io::Error>
+where
+    F: Fn(&str) -> GopherType,
+{
+    let mut posts = Vec::new();
+    for p_entry in std::fs::read_dir(path)? {
+        let entry = p_entry?;
+        if !entry.file_type()?.is_file() {
+            continue;
+        }
+        let name = match entry.file_name().into_string() {
+            Ok(n) => n,
+            Err(_) => continue,
+        };
+        let date = match Date::from_file_name(&name) {
+            Some(d) => d,
+            None => continue,
+        };
+        let post_selector =
+            format!("{}/{}", selector.trim_end_matches('/'), name);
+        let title = read_title(&entry.path()).unwrap_or_else(|| {
+            let slug = name[10..].trim_start_matches('-');
+            let slug = match slug.rfind('.') {
+                Some(i) => &slug[..i],
+                None => slug,
+            };
+            slug.replace('-', " ")
+        });
+        posts.push(Post {
+            date: date,
+            title: title,
+            gtype: gtype(&post_selector),
+            selector: post_selector,
+        });
+    }
+    posts.sort_by(|a, b| {
+        b.date
+            .cmp(&a.date)
+            .then_with(|| b.selector.cmp(&a.selector))
+    });
+    Ok(posts)
+}
+
+fn post_entry(post: &Post, host: &str, port: u16) -> DirectoryEntry {
+    DirectoryEntry {
+        gtype: post.gtype.clone(),
+        description: format!("{} {}", post.date, post.title),
+        selector: post.selector.clone(),
+        host: host.to_string(),
+        port: port,
+    }
+}
+
+fn link(
+    gtype: GopherType,
+    description: &str,
+    selector: String,
+    host: &str,
+    port: u16,
+) -> DirectoryEntry {
+    DirectoryEntry {
+        gtype: gtype,
+        description: description.to_string(),
+        selector: selector,
+        host: host.to_string(),
+        port: port,
+    }
+}
+
+/// Generates page `page` of the reverse-chronological index, `None` if there
+/// is no such page.
+///
+/// # Examples
+///
+/// ```
+/// let m = phlog::index(&posts, "/phlog", "My phlog", 1, 10, "localhost", 70)
+///     .unwrap();
+/// ```
+pub fn index(
+    posts: &[Post],
+    selector: &str,
+    title: &str,
+    page: usize,
+    perpage: usize,
+    host: &str,
+    port: u16,
+) -> Option<Gophermap> {
+    let base = selector.trim_end_matches('/');
+    let perpage = std::cmp::max(perpage, 1);
+    let pages = std::cmp::max((posts.len() + perpage - 1) / perpage, 1);
+    if page < 1 || page > pages {
+        return None;
+    }
+    let mut map = Gophermap::new();
+
+    map.entries.push(DirectoryEntry::info(title.to_string()));
+    if pages > 1 {
+        map.entries
+            .push(DirectoryEntry::info(format!("Page {} of {}", page, pages)));
+    }
+    map.entries.push(DirectoryEntry::info(String::new()));
+
+    for post in posts.iter().skip((page - 1) * perpage).take(perpage) {
+        map.entries.push(post_entry(post, host, port));
+    }
+
+    map.entries.push(DirectoryEntry::info(String::new()));
+    if page > 1 {
+        let newer = match page - 1 {
+            1 => format!("{}/", base),
+            n => format!("{}/page/{}", base, n),
+        };
+        map.entries.push(link(
+            GopherType::Directory,
+            "Newer posts",
+            newer,
+            host,
+            port,
+        ));
+    }
+    if page < pages {
+        map.entries.push(link(
+            GopherType::Directory,
+            "Older posts",
+            format!("{}/page/{}", base, page + 1),
+            host,
+            port,
+        ));
+    }
+    map.entries.push(link(
+        GopherType::Directory,
+        "Archive",
+        format!("{}/archive", base),
+        host,
+        port,
+    ));
+    map.entries.push(link(
+        GopherType::File,
+        "Atom feed",
+        format!("{}/{}", base, FEED),
+        host,
+        port,
+    ));
+    Some(map)
+}
+
+/// Generates an archive menu. Without a year all years are listed, with a
+/// year all months of that year, and with both all posts of that month.
+pub fn archive(
+    posts: &[Post],
+    selector: &str,
+    year: Option<u16>,
+    month: Option<u8>,
+    host: &str,
+    port: u16,
+) -> Gophermap {
+    let base = format!("{}/archive", selector.trim_end_matches('/'));
+    let mut map = Gophermap::new();
+
+    match (year, month) {
+        (None, _) => {
+            map.entries
+                .push(DirectoryEntry::info("Archive".to_string()));
+            map.entries.push(DirectoryEntry::info(String::new()));
+            let mut years: Vec<u16> =
+                posts.iter().map(|p| p.date.year).collect();
+            years.dedup();
+            for y in years {
+                let count = posts.iter().filter(|p| p.date.year == y).count();
+                map.entries.push(link(
+                    GopherType::Directory,
+                    &format!("{} ({} posts)", y, count),
+                    format!("{}/{}", base, y),
+                    host,
+                    port,
+                ));
+            }
+        }
+        (Some(y), None) => {
+            map.entries
+                .push(DirectoryEntry::info(format!("Archive {}", y)));
+            map.entries.push(DirectoryEntry::info(String::new()));
+            let mut months: Vec<u8> = posts
+                .iter()
+                .filter(|p| p.date.year == y)
+                .map(|p| p.date.month)
+                .collect();
+            months.dedup();
+            for m in months {
+                let count = posts
+                    .iter()
+                    .filter(|p| p.date.year == y && p.date.month == m)
+                    .count();
+                map.entries.push(link(
+                    GopherType::Directory,
+                    &format!(
+                        "{} {} ({} posts)",
+                        MONTHS[m as usize - 1],
+                        y,
+                        count
+                    ),
+                    format!("{}/{}/{:02}", base, y, m),
+                    host,
+                    port,
+                ));
+            }
+        }
+        (Some(y), Some(m)) => {
+            let name = MONTHS.get((m as usize).wrapping_sub(1)).unwrap_or(&"");
+            map.entries
+                .push(DirectoryEntry::info(format!("Archive {} {}", name, y)));
+            map.entries.push(DirectoryEntry::info(String::new()));
+            for post in posts
+                .iter()
+                .filter(|p| p.date.year == y && p.date.month == m)
+            {
+                map.entries.push(post_entry(post, host, port));
+            }
+        }
+    }
+    map
+}
+
+/// Escapes the characters XML treats specially.
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Generates an atom feed of the newest posts, linking to them with gopher
+/// URLs.
+pub fn atom(
+    posts: &[Post],
+    selector: &str,
+    title: &str,
+    host: &str,
+    port: u16,
+) -> String {
+    let base = selector.trim_end_matches('/');
+    let url = |gtype: &GopherType, selector: &str| {
+        xml_escape(
+            &GopherUrl {
+                host: host.to_string(),
+                port: port,
+                gtype: gtype.clone(),
+                selector: selector.to_string(),
+                search: None,
+                gopherplus: None,
+            }
+            .to_string(),
+        )
+    };
+    let timestamp = |d: &Date| format!("{}T00:00:00Z", d);
+    let updated = posts
+        .first()
+        .map(|p| timestamp(&p.date))
+        .unwrap_or_else(|| "1970-01-01T00:00:00Z".to_string());
+
+    let mut feed = String::new();
+    feed.push_str("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n");
+    feed.push_str("<feed xmlns=\"http://www.w3.org/2005/Atom\">\n");
+    feed.push_str(&format!("  <title>{}</title>\n", xml_escape(title)));
+    feed.push_str(&format!(
+        "  <id>{}</id>\n",
+        url(&GopherType::Directory, base)
+    ));
+    feed.push_str(&format!(
+        "  <link href=\"{}\"/>\n",
+        url(&GopherType::Directory, base)
+    ));
+    feed.push_str(&format!(
+        "  <link rel=\"self\" href=\"{}\"/>\n",
+        url(&GopherType::File, &format!("{}/{}", base, FEED))
+    ));
+    feed.push_str(&format!("  <updated>{}</updated>\n", updated));
+    feed.push_str(&format!(
+        "  <author><name>{}</name></author>\n",
+        xml_escape(host)
+    ));
+    for post in posts.iter().take(FEED_ENTRIES) {
+        let u = url(&post.gtype, &post.selector);
+        feed.push_str("  <entry>\n");
+        feed.push_str(&format!(
+            "    <title>{}</title>\n",
+            xml_escape(&post.title)
+        ));
+        feed.push_str(&format!("    <id>{}</id>\n", u));
+        feed.push_str(&format!("    <link href=\"{}\"/>\n", u));
+        feed.push_str(&format!(
+            "    <updated>{}</updated>\n",
+            timestamp(&post.date)
+        ));
+        feed.push_str("  </entry>\n");
+    }
+    feed.push_str("</feed>\n");
+    feed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn post(y: u16, m: u8, d: u8, title: &str) -> Post {
+        Post {
+            date: Date {
+                year: y,
+                month: m,
+                day: d,
+            },
+            title: title.to_string(),
+            selector: format!("/phlog/{:04}-{:02}-{:02}.txt", y, m, d),
+            gtype: GopherType::File,
+        }
+    }
+
+    #[test]
+    fn test_date_from_file_name() {
+        assert_eq!(
+            Date::from_file_name("2018-06-01-hello.md"),
+            Some(Date {
+                year: 2018,
+                month: 6,
+                day: 1
+            })
+        );
+        assert_eq!(Date::from_file_name("2018-13-01.md"), None);
+        assert_eq!(Date::from_file_name("2018-06-31.md"), None);
+        assert_eq!(Date::from_file_name("2018-02-29.md"), None);
+        assert!(Date::from_file_name("2016-02-29.md").is_some());
+        assert!(Date::from_file_name("2018-12-31.md").is_some());
+        assert_eq!(Date::from_file_name("hello.md"), None);
+        assert_eq!(Date::from_file_name("about-this-phlog.txt"), None);
+    }
+
+    #[test]
+    fn test_request_from_str() {
+        assert_eq!(Request::from_str(""), Some(Request::Index(1)));
+        assert_eq!(Request::from_str("/"), Some(Request::Index(1)));
+        assert_eq!(Request::from_str("page/3"), Some(Request::Index(3)));
+        assert_eq!(Request::from_str("page/0"), None);
+        assert_eq!(Request::from_str("atom.xml"), Some(Request::Feed));
+        assert_eq!(
+            Request::from_str("archive/2018/06"),
+            Some(Request::Archive(Some(2018), Some(6)))
+        );
+        assert_eq!(Request::from_str("2018-06-01-hello.md"), None);
+    }
+
+    #[test]
+    fn test_index_pagination() {
+        let posts = vec![
+            post(2018, 7, 2, "Third"),
+            post(2018, 6, 2, "Second"),
+            post(2017, 12, 24, "First"),
+        ];
+        let map =
+            index(&posts, "/phlog", "Test", 2, 2, "localhost", 70).unwrap();
+        let descriptions: Vec<&str> =
+            map.entries.iter().map(|e| e.description.as_str()).collect();
+        assert_eq!(
+            descriptions,
+            vec![
+                "Test",
+                "Page 2 of 2",
+                "",
+                "2017-12-24 First",
+                "",
+                "Newer posts",
+                "Archive",
+                "Atom feed",
+            ]
+        );
+        assert_eq!(map.entries[5].selector, "/phlog/");
+        assert!(
+            index(&posts, "/phlog", "Test", 3, 2, "localhost", 70).is_none()
+        );
+        assert!(index(&[], "/phlog", "Test", 1, 2, "localhost", 70).is_some());
+    }
+
+    #[test]
+    fn test_archive() {
+        let posts = vec![
+            post(2018, 7, 2, "Third"),
+            post(2018, 6, 2, "Second"),
+            post(2018, 6, 1, "First"),
+        ];
+        let map = archive(&posts, "/phlog", Some(2018), None, "localhost", 70);
+        assert_eq!(map.entries[2].description, "July 2018 (1 posts)");
+        assert_eq!(map.entries[3].description, "June 2018 (2 posts)");
+        assert_eq!(map.entries[3].selector, "/phlog/archive/2018/06");
+    }
+
+    #[test]
+    fn test_atom() {
+        let posts = vec![post(2018, 6, 2, "Fish & Chips")];
+        let feed = atom(&posts, "/phlog", "Test", "localhost", 70);
+        assert!(feed.contains("<title>Fish &amp; Chips</title>"));
+        assert!(feed.contains(
+            "<link href=\"gopher://localhost:70/0/phlog/2018-06-02.txt\"/>"
+        ));
+        assert!(feed.contains("<updated>2018-06-02T00:00:00Z</updated>"));
+    }
+}
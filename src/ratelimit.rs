@@ -0,0 +1,241 @@
+/*
+ *    Copyright (C) 2016-2018 Stefan Luecke
+ *                  2018 Nicolas Martin
+ *
+ *    This program is free software: you can redistribute it and/or modify
+ *    it under the terms of the GNU Affero General Public License as published
+ *    by the Free Software Foundation, either version 3 of the License, or
+ *    (at your option) any later version.
+ *
+ *    This program is distributed in the hope that it will be useful,
+ *    but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *    GNU Affero General Public License for more details.
+ *
+ *    You should have received a copy of the GNU Affero General Public License
+ *    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ *    Authors: Stefan Luecke <glaxx@glaxx.net>
+ *             Nicolas Martin <penguwingit@gmail.com>
+ */
+//! Limits on the connections and requests of gopher clients.
+use super::std;
+use cidr::{self, Network};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use Limits;
+
+/// Number of request windows kept. Once there are more, the expired ones are
+/// dropped, or the oldest one if none has expired.
+const MAX_WINDOWS: usize = 1024;
+
+/// Why a connection was refused.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Refusal {
+    /// The server handles too many connections.
+    Connections,
+    /// The client has too many open connections.
+    PerIp,
+    /// The network of the client sent too many requests this minute.
+    PerMinute,
+}
+
+impl Refusal {
+    /// Returns the name of the limit which was exceeded.
+    pub fn limit(&self) -> &'static str {
+        match *self {
+            Refusal::Connections => "connections",
+            Refusal::PerIp => "perip",
+            Refusal::PerMinute => "perminute",
+        }
+    }
+}
+
+impl std::fmt::Display for Refusal {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(match *self {
+            Refusal::Connections => "Too many connections, try again later",
+            Refusal::PerIp => "Too many connections from your address",
+            Refusal::PerMinute => "Too many requests, try again in a minute",
+        })
+    }
+}
+
+/// The requests of a network in the minute starting at `start`.
+struct Window {
+    start: Instant,
+    requests: u32,
+}
+
+#[derive(Default)]
+struct State {
+    connections: usize,
+    peers: HashMap<IpAddr, usize>,
+    windows: HashMap<Network, Window>,
+}
+
+/// Keeps track of the open connections and recent requests of all clients.
+#[derive(Default)]
+pub struct Limiter {
+    state: Mutex<State>,
+}
+
+impl Limiter {
+    pub fn new() -> Limiter {
+        Limiter::default()
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().expect("limiter lock poisoned")
+    }
+
+    /// Counts a connection from `peer` accepted at `now`, unless it exceeds
+    /// one of the `limits`. Admitted connections have to be released once
+    /// they are closed.
+    pub(crate) fn admit(
+        &self,
+        peer: &IpAddr,
+        limits: &Limits,
+        now: Instant,
+    ) -> Result<(), Refusal> {
+        let peer = cidr::canonical(peer);
+        let mut state = self.state();
+        if let Some(max) = limits.connections {
+            if state.connections >= max {
+                return Err(Refusal::Connections);
+            }
+        }
+        if let Some(max) = limits.perip {
+            if state.peers.get(&peer).map_or(false, |&n| n >= max) {
+                return Err(Refusal::PerIp);
+            }
+        }
+        if let Some(max) = limits.perminute {
+            let minute = Duration::from_secs(60);
+            let prefix = match peer {
+                IpAddr::V4(_) => limits.ipv4prefix,
+                IpAddr::V6(_) => limits.ipv6prefix,
+            };
+            let network = Network::containing(&peer, prefix);
+            if state.windows.len() >= MAX_WINDOWS
+                && !state.windows.contains_key(&network)
+            {
+                state
+                    .windows
+                    .retain(|_, w| now.duration_since(w.start) < minute);
+            }
+            if state.windows.len() >= MAX_WINDOWS
+                && !state.windows.contains_key(&network)
+            {
+                let oldest = state
+                    .windows
+                    .iter()
+                    .min_by_key(|&(_, w)| w.start)
+                    .map(|(n, _)| n.clone());
+                if let Some(oldest) = oldest {
+                    state.windows.remove(&oldest);
+                }
+            }
+            let window = state.windows.entry(network).or_insert(Window {
+                start: now,
+                requests: 0,
+            });
+            if now.duration_since(window.start) >= minute {
+                window.start = now;
+                window.requests = 0;
+            }
+            if window.requests >= max {
+                return Err(Refusal::PerMinute);
+            }
+            window.requests += 1;
+        }
+        state.connections += 1;
+        *state.peers.entry(peer).or_insert(0) += 1;
+        Ok(())
+    }
+
+    /// Releases an admitted connection from `peer` after it was closed.
+    pub fn release(&self, peer: &IpAddr) {
+        let peer = cidr::canonical(peer);
+        let mut state = self.state();
+        state.connections = state.connections.saturating_sub(1);
+        let open = match state.peers.get_mut(&peer) {
+            Some(n) => {
+                *n -= 1;
+                *n
+            }
+            None => return,
+        };
+        if open == 0 {
+            state.peers.remove(&peer);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use toml;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_connections() {
+        let limits: Limits =
+            toml::from_str("connections = 3\nperip = 2\n").unwrap();
+        let limiter = Limiter::new();
+        let now = Instant::now();
+        let (a, b) = (ip("192.0.2.1"), ip("::ffff:192.0.2.1"));
+        assert_eq!(limiter.admit(&a, &limits, now), Ok(()));
+        assert_eq!(limiter.admit(&b, &limits, now), Ok(()));
+        assert_eq!(limiter.admit(&a, &limits, now), Err(Refusal::PerIp));
+        assert_eq!(limiter.admit(&ip("192.0.2.2"), &limits, now), Ok(()));
+        assert_eq!(
+            limiter.admit(&ip("192.0.2.3"), &limits, now),
+            Err(Refusal::Connections)
+        );
+        limiter.release(&b);
+        assert_eq!(limiter.admit(&a, &limits, now), Ok(()));
+    }
+
+    #[test]
+    fn test_perminute() {
+        let limits: Limits =
+            toml::from_str("perminute = 2\nipv4prefix = 24\n").unwrap();
+        let limiter = Limiter::new();
+        let now = Instant::now();
+        for peer in &["198.51.100.1", "198.51.100.2"] {
+            assert_eq!(limiter.admit(&ip(peer), &limits, now), Ok(()));
+            limiter.release(&ip(peer));
+        }
+        assert_eq!(
+            limiter.admit(&ip("198.51.100.3"), &limits, now),
+            Err(Refusal::PerMinute)
+        );
+        assert_eq!(limiter.admit(&ip("198.51.101.1"), &limits, now), Ok(()));
+        let later = now + Duration::from_secs(60);
+        assert_eq!(limiter.admit(&ip("198.51.100.3"), &limits, later), Ok(()));
+    }
+
+    #[test]
+    fn test_max_windows() {
+        let limits: Limits = toml::from_str("perminute = 1\n").unwrap();
+        let limiter = Limiter::new();
+        let now = Instant::now();
+        for i in 0..MAX_WINDOWS as u32 + 10 {
+            let peer = IpAddr::from(std::net::Ipv4Addr::from(0x0a00_0000 + i));
+            let at = now + Duration::from_millis(u64::from(i));
+            assert_eq!(limiter.admit(&peer, &limits, at), Ok(()));
+            limiter.release(&peer);
+        }
+        let state = limiter.state();
+        assert_eq!(state.windows.len(), MAX_WINDOWS);
+        // The oldest windows were forgotten
+        let first = Network::containing(&ip("10.0.0.0"), 32);
+        assert!(!state.windows.contains_key(&first));
+    }
+}
@@ -0,0 +1,323 @@
+/*
+ *    Copyright (C) 2016-2018 Stefan Luecke
+ *                  2018 Nicolas Martin
+ *
+ *    This program is free software: you can redistribute it and/or modify
+ *    it under the terms of the GNU Affero General Public License as published
+ *    by the Free Software Foundation, either version 3 of the License, or
+ *    (at your option) any later version.
+ *
+ *    This program is distributed in the hope that it will be useful,
+ *    but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *    GNU Affero General Public License for more details.
+ *
+ *    You should have received a copy of the GNU Affero General Public License
+ *    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ *    Authors: Stefan Luecke <glaxx@glaxx.net>
+ *             Nicolas Martin <penguwingit@gmail.com>
+ */
+//! Server status menus generated from statistics about the connections.
+use accesslog::{Record, Status};
+use cache;
+use directoryentry::DirectoryEntry;
+use gophermap::Gophermap;
+use gophertype::GopherType;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// Number of selectors and peers listed on their pages.
+const TOP: usize = 20;
+
+/// Number of selectors and peers counted. Once there are more, the least
+/// requested half is forgotten.
+const MAX_TRACKED: usize = 1024;
+
+/// A connection in progress.
+struct Connection {
+    peer: SocketAddr,
+    selector: Option<String>,
+    since: Instant,
+}
+
+#[derive(Default)]
+struct Counters {
+    next_id: u64,
+    requests: u64,
+    failed: u64,
+    refused: u64,
+    selectors: HashMap<String, u64>,
+    peers: HashMap<IpAddr, u64>,
+    connections: BTreeMap<u64, Connection>,
+}
+
+/// Statistics about the gopher connections since we were started.
+pub struct Statistics {
+    started: Instant,
+    counters: Mutex<Counters>,
+}
+
+/// Increments the count of `key`, forgetting the least frequent keys if
+/// there are too many.
+fn count<K: Hash + Eq + Clone>(map: &mut HashMap<K, u64>, key: K) {
+    *map.entry(key).or_insert(0) += 1;
+    if map.len() > MAX_TRACKED {
+        let keep: Vec<(K, u64)> = top(map, MAX_TRACKED / 2);
+        *map = keep.into_iter().collect();
+    }
+}
+
+/// Returns the `n` most frequent keys of `map`.
+fn top<K: Hash + Eq + Clone>(map: &HashMap<K, u64>, n: usize) -> Vec<(K, u64)> {
+    let mut entries: Vec<(K, u64)> =
+        map.iter().map(|(k, &c)| (k.clone(), c)).collect();
+    entries.sort_by(|a, b| b.1.cmp(&a.1));
+    entries.truncate(n);
+    entries
+}
+
+/// Formats a duration like `3d 04:05:06`.
+fn format_duration(d: Duration) -> String {
+    let secs = d.as_secs();
+    let clock = format!(
+        "{:02}:{:02}:{:02}",
+        secs / 3600 % 24,
+        secs / 60 % 60,
+        secs % 60
+    );
+    match secs / 86400 {
+        0 => clock,
+        days => format!("{}d {}", days, clock),
+    }
+}
+
+impl Statistics {
+    pub fn new() -> Statistics {
+        Statistics {
+            started: Instant::now(),
+            counters: Mutex::new(Counters::default()),
+        }
+    }
+
+    fn counters(&self) -> MutexGuard<'_, Counters> {
+        self.counters.lock().expect("statistics lock poisoned")
+    }
+
+    /// Registers a connection accepted just now, returning its id.
+    pub fn accepted(&self, peer: SocketAddr) -> u64 {
+        let mut counters = self.counters();
+        let id = counters.next_id;
+        counters.next_id += 1;
+        counters.connections.insert(
+            id,
+            Connection {
+                peer: peer,
+                selector: None,
+                since: Instant::now(),
+            },
+        );
+        id
+    }
+
+    /// Counts a connection refused for exceeding a limit.
+    pub fn refused(&self) {
+        self.counters().refused += 1;
+    }
+
+    /// Notes the selector connection `id` requested.
+    pub fn requested(&self, id: u64, selector: &str) {
+        if let Some(c) = self.counters().connections.get_mut(&id) {
+            c.selector = Some(selector.to_string());
+        }
+    }
+
+    /// Counts the request of connection `id` once it has been closed.
+    pub fn finished(&self, id: u64, record: &Record) {
+        let mut counters = self.counters();
+        counters.connections.remove(&id);
+        counters.requests += 1;
+        if record.status != Status::Ok {
+            counters.failed += 1;
+        }
+        if let Some(ref selector) = record.selector {
+            count(&mut counters.selectors, selector.clone());
+        }
+        count(&mut counters.peers, record.peer.ip());
+    }
+
+    /// Renders the status menu `page` below `prefix`, i.e. the overview for
+    /// an empty `page`. Returns `None` for unknown pages.
+    ///
+    /// # Arguments
+    ///
+    /// * `prefix` - The selector of the overview.
+    /// * `page` - The rest of the requested selector.
+    /// * `host` - Our host name, used for links.
+    /// * `port` - Our gopher port, used for links.
+    /// * `cache` - Statistics of the content cache, shown in the overview.
+    pub fn menu(
+        &self,
+        prefix: &str,
+        page: &str,
+        host: &str,
+        port: u16,
+        cache: &cache::Stats,
+    ) -> Option<Gophermap> {
+        let counters = self.counters();
+        let mut map = Gophermap::new();
+        let mut info = |s: String| map.entries.push(DirectoryEntry::info(s));
+        match page.trim_end_matches('/') {
+            "" => {
+                info(format!(
+                    "{} {} server status",
+                    env!("CARGO_PKG_NAME"),
+                    env!("CARGO_PKG_VERSION")
+                ));
+                info(String::new());
+                info(format!(
+                    "Uptime:      {}",
+                    format_duration(self.started.elapsed())
+                ));
+                info(format!(
+                    "Requests:    {} ({} failed)",
+                    counters.requests, counters.failed
+                ));
+                info(format!("Connections: {}", counters.connections.len()));
+                info(format!("Refused:     {}", counters.refused));
+                info(format!(
+                    "Cache:       {} items, {} KiB, {} hits, {} misses",
+                    cache.entries,
+                    cache.size / 1024,
+                    cache.hits,
+                    cache.misses
+                ));
+                info(String::new());
+            }
+            "/selectors" => {
+                info("Most requested selectors".to_string());
+                info(String::new());
+                for (selector, n) in top(&counters.selectors, TOP) {
+                    info(format!("{:>8}  {}", n, selector));
+                }
+            }
+            "/peers" => {
+                info("Most active clients".to_string());
+                info(String::new());
+                for (peer, n) in top(&counters.peers, TOP) {
+                    info(format!("{:>8}  {}", n, peer));
+                }
+            }
+            "/connections" => {
+                info("Connections in progress".to_string());
+                info(String::new());
+                for c in counters.connections.values() {
+                    info(format!(
+                        "{:>8}  {}  {}",
+                        format_duration(c.since.elapsed()),
+                        c.peer,
+                        c.selector.as_ref().map_or("-", |s| s.as_str())
+                    ));
+                }
+            }
+            _ => return None,
+        }
+
+        let base = prefix.trim_end_matches('/');
+        let link = |description: &str, page: &str| DirectoryEntry {
+            gtype: GopherType::Directory,
+            description: description.to_string(),
+            selector: format!("{}{}", base, page),
+            host: host.to_string(),
+            port: port,
+        };
+        if page.trim_end_matches('/').is_empty() {
+            map.entries
+                .push(link("Most requested selectors", "/selectors"));
+            map.entries.push(link("Most active clients", "/peers"));
+            map.entries
+                .push(link("Connections in progress", "/connections"));
+        } else {
+            map.entries.push(DirectoryEntry::info(String::new()));
+            map.entries.push(link("Back to the server status", ""));
+        }
+        Some(map)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_menu() {
+        let stats = Statistics::new();
+        let peer: SocketAddr = "127.0.0.1:51234".parse().unwrap();
+        for selector in &["/phlog", "/", "/phlog"] {
+            let id = stats.accepted(peer);
+            stats.requested(id, selector);
+            let mut record = Record::new(peer);
+            record.selector = Some(selector.to_string());
+            record.status = Status::Ok;
+            stats.finished(id, &record);
+        }
+        let id = stats.accepted(peer);
+        stats.requested(id, "/slow");
+        stats.refused();
+
+        let cache = cache::Stats {
+            entries: 3,
+            size: 4096,
+            hits: 10,
+            misses: 3,
+        };
+        let descriptions = |page: &str| -> Vec<String> {
+            stats
+                .menu("/server-status", page, "localhost", 70, &cache)
+                .unwrap()
+                .entries
+                .into_iter()
+                .map(|e| e.description)
+                .collect()
+        };
+        let overview = descriptions("");
+        assert_eq!(overview[3], "Requests:    3 (0 failed)");
+        assert_eq!(overview[4], "Connections: 1");
+        assert_eq!(overview[5], "Refused:     1");
+        assert_eq!(
+            overview[6],
+            "Cache:       3 items, 4 KiB, 10 hits, 3 misses"
+        );
+        let selectors = descriptions("/selectors");
+        assert_eq!(selectors[2], "       2  /phlog");
+        assert_eq!(selectors[3], "       1  /");
+        assert_eq!(descriptions("/peers")[2], "       3  127.0.0.1");
+        assert!(
+            descriptions("/connections")[2].ends_with("127.0.0.1:51234  /slow")
+        );
+        assert!(stats
+            .menu("/server-status", "/nope", "localhost", 70, &cache)
+            .is_none());
+    }
+
+    #[test]
+    fn test_count() {
+        let mut map = HashMap::new();
+        for i in 0..MAX_TRACKED {
+            count(&mut map, i);
+        }
+        count(&mut map, 0);
+        count(&mut map, MAX_TRACKED);
+        assert_eq!(map.len(), MAX_TRACKED / 2);
+        assert_eq!(map[&0], 2);
+    }
+
+    #[test]
+    fn test_format_duration() {
+        assert_eq!(format_duration(Duration::from_secs(62)), "00:01:02");
+        assert_eq!(format_duration(Duration::from_secs(273906)), "3d 04:05:06");
+    }
+}
@@ -0,0 +1,273 @@
+/*
+ *    Copyright (C) 2016-2018 Stefan Luecke
+ *                  2018 Nicolas Martin
+ *
+ *    This program is free software: you can redistribute it and/or modify
+ *    it under the terms of the GNU Affero General Public License as published
+ *    by the Free Software Foundation, either version 3 of the License, or
+ *    (at your option) any later version.
+ *
+ *    This program is distributed in the hope that it will be useful,
+ *    but WITHOUT ANY WARRANTY; without even the implied warranty of
+ *    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ *    GNU Affero General Public License for more details.
+ *
+ *    You should have received a copy of the GNU Affero General Public License
+ *    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ *    Authors: Stefan Luecke <glaxx@glaxx.net>
+ *             Nicolas Martin <penguwingit@gmail.com>
+ */
+//! systemd integration: socket activation and service state notifications.
+use super::std;
+use libc;
+use std::env;
+use std::io::Error;
+use std::net::{SocketAddr, TcpListener};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::UnixDatagram;
+use std::time::Duration;
+
+/// The first file descriptor passed by systemd.
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Listening sockets passed by systemd.
+pub struct Sockets {
+    /// The sockets with the name given by `FileDescriptorName=`, if any.
+    sockets: Vec<(Option<String>, TcpListener)>,
+}
+
+impl Sockets {
+    /// Takes over the sockets systemd passed to us and removes the variables
+    /// announcing them from the environment, so children don't pick them up.
+    pub fn from_env() -> Sockets {
+        let ours = env::var("LISTEN_PID")
+            .ok()
+            .and_then(|p| p.parse::<u32>().ok())
+            == Some(std::process::id());
+        let count = env::var("LISTEN_FDS")
+            .ok()
+            .and_then(|n| n.parse::<RawFd>().ok())
+            .unwrap_or(0);
+        let names: Vec<String> = env::var("LISTEN_FDNAMES")
+            .map(|n| n.split(':').map(|s| s.to_string()).collect())
+            .unwrap_or_default();
+        for var in &["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+            env::remove_var(var);
+        }
+        if !ours {
+            return Sockets {
+                sockets: Vec::new(),
+            };
+        }
+
+        let mut sockets = Vec::new();
+        for (i, fd) in (LISTEN_FDS_START..LISTEN_FDS_START + count).enumerate()
+        {
+            let listener = unsafe {
+                libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+                TcpListener::from_raw_fd(fd)
+            };
+            // Anything but TCP sockets is of no use to us
+            if listener.local_addr().is_err() {
+                continue;
+            }
+            let name = names
+                .get(i)
+                .filter(|n| !n.is_empty() && *n != "unknown")
+                .cloned();
+            sockets.push((name, listener));
+        }
+        Sockets { sockets: sockets }
+    }
+
+    /// Takes the socket named `name` or else the one bound to `addr`.
+    pub fn take(
+        &mut self,
+        name: &str,
+        addr: &SocketAddr,
+    ) -> Option<TcpListener> {
+        let position = self
+            .sockets
+            .iter()
+            .position(|&(ref n, _)| {
+                n.as_ref().map(|n| n.as_str()) == Some(name)
+            })
+            .or_else(|| {
+                self.sockets.iter().position(|&(_, ref l)| {
+                    l.local_addr().ok().as_ref() == Some(addr)
+                })
+            })?;
+        Some(self.sockets.remove(position).1)
+    }
+
+    /// Takes the first socket without a name, if any.
+    pub fn take_unnamed(&mut self) -> Option<TcpListener> {
+        let position =
+            self.sockets.iter().position(|&(ref n, _)| n.is_none())?;
+        Some(self.sockets.remove(position).1)
+    }
+
+    /// Returns the addresses of the sockets nobody took.
+    pub fn remaining(&self) -> Vec<String> {
+        self.sockets
+            .iter()
+            .map(|&(_, ref l)| {
+                l.local_addr().map(|a| a.to_string()).unwrap_or_default()
+            })
+            .collect()
+    }
+}
+
+/// Sends `state` to the notification socket at `path`. Paths starting with
+/// `@` denote abstract sockets.
+fn notify_to(path: &str, state: &str) -> Result<(), Error> {
+    let socket = UnixDatagram::unbound()?;
+    if path.starts_with('@') {
+        send_to_abstract(&socket, &path.as_bytes()[1..], state.as_bytes())?;
+    } else {
+        socket.send_to(state.as_bytes(), path)?;
+    }
+    Ok(())
+}
+
+/// Sends `data` to the abstract socket `name`. The standard library can't
+/// address those, so the `sockaddr_un` is built by hand: the name follows a
+/// leading NUL byte and isn't terminated.
+fn send_to_abstract(
+    socket: &UnixDatagram,
+    name: &[u8],
+    data: &[u8],
+) -> Result<(), Error> {
+    let mut addr: libc::sockaddr_un = unsafe { std::mem::zeroed() };
+    addr.sun_family = libc::AF_UNIX as libc::sa_family_t;
+    if name.len() + 1 > addr.sun_path.len() {
+        return Err(Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "socket name too long",
+        ));
+    }
+    for (dst, src) in addr.sun_path[1..].iter_mut().zip(name) {
+        *dst = *src as libc::c_char;
+    }
+    let path_offset = {
+        let base = &addr as *const _ as usize;
+        &addr.sun_path as *const _ as usize - base
+    };
+    let len = (path_offset + 1 + name.len()) as libc::socklen_t;
+    let sent = unsafe {
+        libc::sendto(
+            socket.as_raw_fd(),
+            data.as_ptr() as *const libc::c_void,
+            data.len(),
+            0,
+            &addr as *const _ as *const libc::sockaddr,
+            len,
+        )
+    };
+    if sent < 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Tells systemd about a change of our state, e.g. `READY=1`. Does nothing if
+/// we haven't been started by systemd.
+///
+/// # Examples
+///
+/// ```
+/// systemd::notify("READY=1")?;
+/// ```
+pub fn notify(state: &str) -> Result<(), Error> {
+    match env::var("NOTIFY_SOCKET") {
+        Ok(path) => notify_to(&path, state),
+        Err(_) => Ok(()),
+    }
+}
+
+/// Returns how often systemd wants to hear `WATCHDOG=1` from us, if at all.
+/// This is half the configured watchdog timeout, leaving some room.
+pub fn watchdog_interval() -> Option<Duration> {
+    if let Ok(pid) = env::var("WATCHDOG_PID") {
+        if pid.parse::<u32>().ok() != Some(std::process::id()) {
+            return None;
+        }
+    }
+    let usec = env::var("WATCHDOG_USEC").ok()?.parse::<u64>().ok()?;
+    if usec == 0 {
+        return None;
+    }
+    Some(Duration::from_micros(usec / 2))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_take() {
+        let http = TcpListener::bind("127.0.0.1:0").unwrap();
+        let gopher = TcpListener::bind("127.0.0.1:0").unwrap();
+        let gopher_addr = gopher.local_addr().unwrap();
+        let other = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut sockets = Sockets {
+            sockets: vec![
+                (Some("http".to_string()), http),
+                (None, gopher),
+                (None, other),
+            ],
+        };
+
+        let unused: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        assert!(sockets.take("http", &unused).is_some());
+        assert!(sockets.take("gemini", &unused).is_none());
+        assert_eq!(
+            sockets
+                .take("gopher", &gopher_addr)
+                .unwrap()
+                .local_addr()
+                .unwrap(),
+            gopher_addr
+        );
+        assert!(sockets.take_unnamed().is_some());
+        assert!(sockets.take_unnamed().is_none());
+        assert!(sockets.remaining().is_empty());
+    }
+
+    #[test]
+    fn test_notify_to() {
+        let path = std::env::temp_dir().join("rusty-gopher-notify-test");
+        let _ = std::fs::remove_file(&path);
+        let socket = UnixDatagram::bind(&path).unwrap();
+        notify_to(path.to_str().unwrap(), "READY=1").unwrap();
+        let mut buf = [0; 16];
+        let len = socket.recv(&mut buf).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(&buf[..len], b"READY=1");
+    }
+
+    #[test]
+    fn test_notify_to_abstract() {
+        let name = format!("rusty-gopher-notify-test-{}", std::process::id());
+        let socket = UnixDatagram::unbound().unwrap();
+        let mut addr: libc::sockaddr_un = unsafe { std::mem::zeroed() };
+        addr.sun_family = libc::AF_UNIX as libc::sa_family_t;
+        for (dst, src) in addr.sun_path[1..].iter_mut().zip(name.bytes()) {
+            *dst = src as libc::c_char;
+        }
+        let len = std::mem::size_of::<libc::sa_family_t>() + 1 + name.len();
+        let bound = unsafe {
+            libc::bind(
+                socket.as_raw_fd(),
+                &addr as *const _ as *const libc::sockaddr,
+                len as libc::socklen_t,
+            )
+        };
+        assert_eq!(bound, 0);
+        notify_to(&format!("@{}", name), "READY=1").unwrap();
+        let mut buf = [0; 16];
+        let len = socket.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"READY=1");
+    }
+}